@@ -0,0 +1,21 @@
+#![no_main]
+
+use bitsrun::xencode::decode_info;
+use bitsrun::xencode::fkbase64;
+use bitsrun::xencode::xencode;
+use bitsrun::xencode::SRBX1_PREFIX;
+use libfuzzer_sys::fuzz_target;
+
+// the first line of the input is the challenge token, the rest is the message
+fuzz_target!(|data: &str| {
+    let (token, msg) = data.split_once('\n').unwrap_or((data, ""));
+
+    // decoding arbitrary input never panics
+    let _ = decode_info(msg, token);
+
+    // encoding never panics, and decodes back to the message if the token is valid
+    if let Ok(encoded) = xencode(msg, token) {
+        let info = format!("{}{}", SRBX1_PREFIX, fkbase64(encoded));
+        assert_eq!(decode_info(&info, token).unwrap(), msg);
+    }
+});
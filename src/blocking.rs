@@ -0,0 +1,335 @@
+//! Blocking (synchronous) counterpart of the async SRUN client, enabled with the `blocking` feature
+//!
+//! The async client only ever awaits its HTTP transport. Here the transport is blocking, so the
+//! futures of the async client are driven on the calling thread by a minimal executor, without an
+//! async runtime. This way the blocking client shares all protocol logic (including the checksum
+//! and `xencode` payload construction) with the async one.
+//!
+//! The shared code must not await runtime-bound futures (e.g., tokio timers or sockets), which is
+//! why `verify` is not part of the blocking API.
+
+use crate::client;
+use crate::client::SrunClientBuilder;
+use crate::client::SrunLoginState;
+use crate::client::SrunPortalResponse;
+use crate::probe::Capabilities;
+use crate::transport::HttpResponse;
+use crate::transport::HttpTransport;
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::ops::Deref;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Wake;
+use std::task::Waker;
+use std::thread;
+use std::thread::Thread;
+
+use anyhow::Context as _;
+use anyhow::Result;
+use reqwest::blocking::Client;
+use reqwest::blocking::ClientBuilder;
+use reqwest::blocking::Response;
+use reqwest::redirect::Policy;
+
+/// Blocking HTTP transport used to talk to the SRUN portal
+pub trait BlockingTransport {
+    /// Send a GET request with query parameters, returning the final URL and response body
+    fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse>;
+
+    /// Send a GET request without following redirects, falls back to `get` if unsupported
+    fn get_no_redirect(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        self.get(url, query)
+    }
+}
+
+fn into_response(resp: Response) -> Result<HttpResponse> {
+    let url = resp.url().to_string();
+    let status = resp.status().as_u16();
+    let headers = resp
+        .headers()
+        .iter()
+        .map(|(key, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (key.to_string(), value)
+        })
+        .collect();
+    let body = resp.text()?;
+    Ok(HttpResponse {
+        url,
+        status,
+        headers,
+        body,
+        request_headers: vec![],
+    })
+}
+
+impl BlockingTransport for Client {
+    fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        into_response(Client::get(self, url).query(query).send()?)
+    }
+}
+
+/// The default blocking transport, a `reqwest` client and a sibling client that never follows
+/// redirects
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: Client,
+    no_redirect: Client,
+}
+
+impl ReqwestTransport {
+    /// Build both clients with the same settings (e.g., local address and timeout)
+    pub fn new(configure: impl Fn(ClientBuilder) -> ClientBuilder) -> Result<ReqwestTransport> {
+        let client = configure(Client::builder())
+            .build()
+            .with_context(|| "failed to build http client")?;
+        let no_redirect = configure(Client::builder())
+            .redirect(Policy::none())
+            .build()
+            .with_context(|| "failed to build http client")?;
+        Ok(ReqwestTransport {
+            client,
+            no_redirect,
+        })
+    }
+}
+
+impl BlockingTransport for ReqwestTransport {
+    fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        into_response(self.client.get(url).query(query).send()?)
+    }
+
+    fn get_no_redirect(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        into_response(self.no_redirect.get(url).query(query).send()?)
+    }
+}
+
+impl<T: BlockingTransport + ?Sized> BlockingTransport for &T {
+    fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        (**self).get(url, query)
+    }
+
+    fn get_no_redirect(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        (**self).get_no_redirect(url, query)
+    }
+}
+
+/// Adapter that runs a blocking transport inline, so that its futures are always ready
+#[derive(Debug, Default)]
+pub struct Inline<T>(pub T);
+
+impl<T: BlockingTransport + Sync> HttpTransport for Inline<T> {
+    async fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        self.0.get(url, query)
+    }
+
+    async fn get_no_redirect(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        self.0.get_no_redirect(url, query)
+    }
+}
+
+/// Wakes the thread that is blocked on a future
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Drive a future to completion on the current thread, parking it while the future is pending
+///
+/// Blocking transports are always ready, so this usually returns after the first poll.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// Get the login state of the current device, or of another device if `ip` is specified
+pub fn get_login_state<T: BlockingTransport + Sync>(
+    client: &T,
+    portal: &str,
+    ip: Option<IpAddr>,
+    verbose: bool,
+) -> Result<SrunLoginState> {
+    // the transport is only borrowed, so wrap the reference instead of taking ownership
+    block_on(client::get_login_state(
+        &Inline(client),
+        portal,
+        ip,
+        verbose,
+    ))
+}
+
+impl SrunClientBuilder {
+    /// Build the blocking SRUN client with `reqwest`
+    pub fn build_blocking(self) -> Result<SrunClient> {
+        let http_client = ReqwestTransport::new(|mut builder| {
+            builder = builder.local_address(self.local_address);
+            if let Some(timeout) = self.timeout {
+                builder = builder.timeout(timeout);
+            }
+            if let Some(user_agent) = &self.user_agent {
+                builder = builder.user_agent(user_agent);
+            }
+            builder
+        })?;
+        Ok(self.build_blocking_with(http_client))
+    }
+
+    /// Build the blocking SRUN client with another blocking HTTP transport
+    pub fn build_blocking_with<T: BlockingTransport + Sync>(self, http_client: T) -> SrunClient<T> {
+        SrunClient {
+            inner: self.build_with(Inline(http_client)),
+        }
+    }
+}
+
+/// Blocking SRUN client, generic over the blocking HTTP transport
+///
+/// Fields of the underlying async client (e.g., `ip`, `ac_id` and `login_state`) are available
+/// through `Deref`.
+#[derive(Debug)]
+pub struct SrunClient<T: BlockingTransport + Sync = ReqwestTransport> {
+    inner: client::SrunClient<Inline<T>>,
+}
+
+impl<T: BlockingTransport + Sync> SrunClient<T> {
+    /// Discover the `ac_id` and IP addresses of the current device that are not yet known
+    pub fn discover(&mut self, verbose: bool) -> Result<()> {
+        block_on(self.inner.discover(verbose))
+    }
+
+    /// Get the login state of the client's IP address, only fetched from the portal once
+    pub fn fetch_login_state(&mut self, verbose: bool) -> Result<SrunLoginState> {
+        block_on(self.inner.fetch_login_state(verbose))
+    }
+
+    /// Probe the capabilities of the portal, only probed once
+    pub fn probe(&mut self, verbose: bool) -> Result<Capabilities> {
+        block_on(self.inner.probe(verbose))
+    }
+
+    /// Login to the SRUN portal
+    pub fn login(&mut self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
+        block_on(self.inner.login(force, verbose))
+    }
+
+    /// Complete a second authentication stage after a successful login, if the network has one
+    pub fn complete_double_auth(&mut self, verbose: bool) -> Result<()> {
+        block_on(self.inner.complete_double_auth(verbose))
+    }
+
+    /// Logout of the SRUN portal
+    pub fn logout(&mut self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
+        block_on(self.inner.logout(force, verbose))
+    }
+}
+
+impl<T: BlockingTransport + Sync> Deref for SrunClient<T> {
+    type Target = client::SrunClient<Inline<T>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::SRUN_PORTAL;
+    use crate::scheme::SrunScheme;
+    use crate::transport::ScriptedTransport;
+
+    const ONLINE: &str = r#"jsonp({"error":"ok","online_ip":"10.62.1.2","client_ip":"10.62.1.2","user_name":"1120201234","online_ip6":"::"})"#;
+    const OFFLINE: &str =
+        r#"jsonp({"error":"not_online_error","online_ip":"10.62.1.2","client_ip":"10.62.1.2"})"#;
+
+    // the scripted transport is always ready, so it can serve as a blocking transport as well
+    impl BlockingTransport for ScriptedTransport {
+        fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+            block_on(HttpTransport::get(self, url, query))
+        }
+    }
+
+    fn client(transport: ScriptedTransport) -> SrunClient<ScriptedTransport> {
+        client::SrunClient::builder()
+            .username("1120201234")
+            .password("hunter2")
+            .ip("10.62.1.2".parse::<IpAddr>().unwrap())
+            .ac_id("1".to_string())
+            .scheme(SrunScheme::default())
+            .build_blocking_with(transport)
+    }
+
+    #[test]
+    fn block_on_waits_for_pending_futures() {
+        let mut polled = false;
+        let output = block_on(std::future::poll_fn(|cx| match polled {
+            true => Poll::Ready(42),
+            false => {
+                polled = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }));
+        assert_eq!(output, 42);
+    }
+
+    #[test]
+    fn blocking_client_logs_in() {
+        let transport = ScriptedTransport::new()
+            .respond(SRUN_PORTAL, OFFLINE)
+            .respond(SRUN_PORTAL, r#"jsonp({"challenge":"8f1c2d3e4b5a6978"})"#)
+            .respond(
+                SRUN_PORTAL,
+                r#"jsonp({"client_ip":"10.62.1.2","online_ip":"10.62.1.2","error":"ok","error_msg":"","res":"ok","suc_msg":"login_ok"})"#,
+            );
+        let mut client = client(transport);
+        let resp = client.login(false, false).unwrap();
+        assert_eq!(resp.suc_msg.as_deref(), Some("login_ok"));
+
+        let requests = client.http_client.0.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].starts_with("http://10.0.0.55/cgi-bin/srun_portal?"));
+        assert!(requests[2].contains("action=login"));
+        assert!(!requests[2].contains("hunter2"));
+    }
+
+    #[test]
+    fn blocking_client_logs_out() {
+        let transport = ScriptedTransport::new().respond(SRUN_PORTAL, ONLINE).respond(
+            SRUN_PORTAL,
+            r#"jsonp({"client_ip":"10.62.1.2","online_ip":"10.62.1.2","error":"ok","error_msg":"","res":"ok"})"#,
+        );
+        let mut client = client(transport);
+        let resp = client.logout(false, false).unwrap();
+        assert_eq!(resp.error, "ok");
+        assert_eq!(
+            client.http_client.0.requests()[1],
+            "http://10.0.0.55/cgi-bin/srun_portal?callback=jsonp&ip=10.62.1.2&username=1120201234&action=logout&ac_id=1"
+        );
+    }
+
+    #[test]
+    fn blocking_get_login_state() {
+        let transport = ScriptedTransport::new().respond(SRUN_PORTAL, ONLINE);
+        let state = get_login_state(&transport, SRUN_PORTAL, None, false).unwrap();
+        assert_eq!(state.user_name.as_deref(), Some("1120201234"));
+    }
+}
@@ -0,0 +1,88 @@
+use crate::net::get_default_gateway;
+use crate::net::get_ip_interface;
+use crate::net::get_local_ip;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use directories::ProjectDirs;
+use url::Url;
+
+/// Path to the ac_id cache file, e.g., `~/.cache/bitsrun/ac_id.json` on Linux
+fn ac_id_cache_path() -> Option<PathBuf> {
+    let dirs = ProjectDirs::from("", "", "bitsrun")?;
+    Some(dirs.cache_dir().join("ac_id.json"))
+}
+
+/// Identify the network this device is on, for caching ac_id per network
+///
+/// The key is the subnet of the local address requests are bound to (`source_ip`), or else used to
+/// reach the portal (`/24` for IPv4, `/64` for IPv6), and the default gateway of its interface if
+/// available, e.g., `10.62.0.0/24 via 10.62.0.1`.
+pub fn network_key(portal: &str, source_ip: Option<IpAddr>) -> Option<String> {
+    let local_ip = match source_ip {
+        Some(ip) => ip,
+        None => {
+            let portal = Url::parse(portal).ok()?;
+            let addr = format!("{}:{}", portal.host_str()?, portal.port_or_known_default()?);
+            get_local_ip(&addr)?
+        }
+    };
+    let subnet = match local_ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.0/24", a, b, c)
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", a, b, c, d)
+        }
+    };
+    let interface = get_ip_interface(local_ip);
+    match get_default_gateway(interface.as_deref()) {
+        Some(gateway) => Some(format!("{} via {}", subnet, gateway)),
+        None => Some(subnet),
+    }
+}
+
+fn read_ac_id_cache() -> BTreeMap<String, String> {
+    ac_id_cache_path()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|cache| serde_json::from_str(&cache).ok())
+        .unwrap_or_default()
+}
+
+/// Get the last discovered ac_id of a network
+pub fn load_ac_id(network: &str) -> Option<String> {
+    read_ac_id_cache().remove(network)
+}
+
+/// Remember the discovered ac_id of a network
+pub fn save_ac_id(network: &str, ac_id: &str) -> Result<()> {
+    let mut cache = read_ac_id_cache();
+    cache.insert(network.into(), ac_id.into());
+    write_ac_id_cache(&cache)
+}
+
+/// Forget the cached ac_id of a network, e.g., after a login with it failed
+pub fn forget_ac_id(network: &str) -> Result<()> {
+    let mut cache = read_ac_id_cache();
+    match cache.remove(network) {
+        Some(_) => write_ac_id_cache(&cache),
+        None => Ok(()),
+    }
+}
+
+fn write_ac_id_cache(cache: &BTreeMap<String, String>) -> Result<()> {
+    let path = ac_id_cache_path().with_context(|| "failed to locate cache directory")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create cache directory `{}`", dir.display()))?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(cache)?)
+        .with_context(|| format!("failed to write ac_id cache `{}`", path.display()))
+}
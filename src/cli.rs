@@ -1,4 +1,5 @@
 use std::net::IpAddr;
+use std::path::PathBuf;
 
 use clap::Args;
 use clap::Parser;
@@ -13,6 +14,36 @@ pub struct Arguments {
     /// Verbose output
     #[arg(short, long, global = true)]
     pub verbose: bool,
+
+    /// Send portal requests through this network interface (e.g., `eth0`)
+    #[arg(long, global = true)]
+    pub interface: Option<String>,
+
+    /// Send portal requests from this local address (takes priority over `--interface`)
+    #[arg(long, global = true)]
+    pub source_ip: Option<IpAddr>,
+
+    /// Record portal requests and responses to a JSON file, with secrets redacted (login, logout,
+    /// status and doctor)
+    #[arg(long, global = true)]
+    pub trace_file: Option<PathBuf>,
+
+    /// Also anonymize usernames and IP addresses in the trace file
+    #[arg(long, global = true, requires = "trace_file")]
+    pub anonymize: bool,
+
+    /// Serve portal responses recorded with `--trace-file` instead of hitting the network
+    #[arg(long, global = true, conflicts_with = "trace_file")]
+    pub replay: Option<PathBuf>,
+
+    /// Never prompt for missing credentials, fail instead (implied if stdin is not a TTY)
+    #[arg(long, global = true)]
+    pub no_input: bool,
+
+    /// Read config files even if they fail security checks, e.g., too open permissions (not
+    /// recommended)
+    #[arg(long, global = true)]
+    pub insecure: bool,
 }
 
 #[derive(Subcommand)]
@@ -31,6 +62,15 @@ pub enum Commands {
 
     /// Poll the server with login requests to keep the session alive
     KeepAlive(DaemonArgs),
+
+    /// Manage logins of LAN hosts listed in the config file (router mode)
+    Router(RouterArgs),
+
+    /// Diagnose connectivity issues step by step
+    Doctor(DoctorArgs),
+
+    /// Decode a captured `{SRBX1}...` login `info` parameter (for debugging)
+    DecodeInfo(DecodeInfoArgs),
 }
 
 #[derive(Args)]
@@ -50,11 +90,36 @@ pub struct ClientArgs {
     #[arg(short, long)]
     pub password: Option<String>,
 
-    /// Manually specify IP address (IPv4)
+    /// Manually specify IP address (IPv4), can be another device's to log it out remotely
     #[arg(long)]
     pub ip: Option<IpAddr>,
 
-    /// Use alternative `dm` logout endpoint for registered dumb terminals
+    /// Manually specify IPv6 address for dual-stack login (implies `--double-stack`)
+    #[arg(long)]
+    pub ip6: Option<IpAddr>,
+
+    /// Login with both IPv4 and IPv6 addresses (dual-stack)
+    #[arg(long)]
+    pub double_stack: bool,
+
+    /// Device OS reported to the portal on login (e.g., `Linux`)
+    #[arg(long)]
+    pub os: Option<String>,
+
+    /// Device name reported to the portal on login (e.g., `bitsrun-rs`)
+    #[arg(long)]
+    pub device_name: Option<String>,
+
+    /// Manually specify ac_id of the current network (skips discovery)
+    #[arg(long)]
+    pub ac_id: Option<String>,
+
+    /// Discover ac_id again instead of using the cached one of the current network
+    #[arg(long)]
+    pub refresh_ac_id: bool,
+
+    /// Registered dumb terminal, login only checks its mac address authentication unless a password
+    /// is given, logout uses the `rad_user_dm` endpoint
     #[arg(long)]
     pub dm: bool,
 
@@ -65,6 +130,42 @@ pub struct ClientArgs {
     /// Force login/logout, don't check login status
     #[arg(short, long)]
     pub force: bool,
+
+    /// Don't ask for confirmation when logging out another device
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Print the computed login request and its intermediate values without sending it (login only)
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Challenge token for `--dry-run`, instead of requesting one from the portal
+    #[arg(long, requires = "dry_run")]
+    pub challenge: Option<String>,
+
+    /// Print the plaintext password in the info data of `--dry-run`, instead of `[redacted]`
+    #[arg(long, requires = "dry_run")]
+    pub show_password: bool,
+
+    /// Verify the device is actually online after login, fail otherwise (login only)
+    #[arg(long, conflicts_with = "dry_run")]
+    pub verify: bool,
+
+    /// URL fetched to verify connectivity, which should return 2xx without redirecting [default: generate_204]
+    #[arg(long, requires = "verify")]
+    pub verify_url: Option<String>,
+
+    /// Resolve a host name to verify connectivity, instead of fetching a URL
+    #[arg(long, requires = "verify", conflicts_with = "verify_url")]
+    pub verify_dns: Option<String>,
+
+    /// Timeout of each verification attempt, in seconds
+    #[arg(long, default_value_t = 5, requires = "verify")]
+    pub verify_timeout: u64,
+
+    /// Verification attempts after the first one fails
+    #[arg(long, default_value_t = 3, requires = "verify")]
+    pub verify_retries: u32,
 }
 
 #[derive(Args)]
@@ -72,4 +173,54 @@ pub struct DaemonArgs {
     /// Path to the config file
     #[arg(short, long)]
     pub config: Option<String>,
+
+    /// Discover ac_id again instead of using the cached one of the current network
+    #[arg(long)]
+    pub refresh_ac_id: bool,
+}
+
+#[derive(Args)]
+pub struct DoctorArgs {
+    /// Optionally provide path to the config file
+    #[arg(short, long)]
+    pub config: Option<String>,
+}
+
+#[derive(Args)]
+pub struct DecodeInfoArgs {
+    /// The `info` parameter, as is or percent-encoded (e.g., copied from a URL)
+    pub info: String,
+
+    /// The challenge token `info` was encrypted with
+    #[arg(short, long)]
+    pub token: String,
+}
+
+#[derive(Args)]
+pub struct RouterArgs {
+    #[command(subcommand)]
+    pub command: RouterCommands,
+
+    /// Path to the config file
+    #[arg(short, long, global = true)]
+    pub config: Option<String>,
+
+    /// Force login/logout, don't check login status
+    #[arg(short, long, global = true)]
+    pub force: bool,
+}
+
+#[derive(Subcommand)]
+pub enum RouterCommands {
+    /// Login all managed hosts
+    Login,
+
+    /// Logout all managed hosts
+    Logout,
+
+    /// Check login status of all managed hosts
+    Status,
+
+    /// Poll the server with login requests to keep all managed hosts alive
+    KeepAlive,
 }
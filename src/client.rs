@@ -1,22 +1,36 @@
 use std::net::IpAddr;
-
-use crate::xencode::fkbase64;
-use crate::xencode::xencode;
+use std::time::Duration;
+
+use crate::detect::ac_id_from_url;
+use crate::detect::default_strategies;
+use crate::detect::detect_ac_id;
+use crate::detect::Strategy;
+use crate::double_auth::check_connectivity;
+use crate::double_auth::is_srun_stage;
+use crate::double_auth::Connectivity;
+use crate::net::get_local_ipv6;
+use crate::probe::probe_capabilities;
+use crate::probe::Capabilities;
+use crate::scheme::LoginInput;
+use crate::scheme::LoginScheme;
+use crate::scheme::SrunScheme;
+use crate::secret::Secret;
+use crate::transport::HttpTransport;
+use crate::transport::ReqwestTransport;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use hmac::Hmac;
-use hmac::Mac;
+use chrono::Utc;
 use md5::Digest;
-use md5::Md5;
 use owo_colors::OwoColorize;
 use owo_colors::Stream::Stdout;
-use reqwest::Client;
 
 use serde::Deserialize;
 use serde::Serialize;
-use serde_json::json;
+use serde_json::Value;
 use sha1::Sha1;
+use url::Url;
+use zeroize::Zeroize;
 
 /// Constants used for the /srun_portal endpoint
 pub const SRUN_PORTAL: &str = "http://10.0.0.55";
@@ -36,6 +50,10 @@ pub struct SrunLoginState {
     pub error: String,
     pub online_ip: IpAddr,
 
+    // present on dual-stack deployments, `::` if not online through IPv6
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub online_ip6: Option<IpAddr>,
+
     // present when logged in
     #[serde(rename = "ServerFlag", skip_serializing_if = "Option::is_none")]
     pub server_flag: Option<i64>,
@@ -93,20 +111,34 @@ pub struct SrunLoginState {
     pub st: Option<i64>,
 }
 
-/// Get the login state of the current device
-pub async fn get_login_state(client: &Client, verbose: bool) -> Result<SrunLoginState> {
+impl SrunLoginState {
+    /// The online IPv6 address, if the portal reports one
+    pub fn online_ipv6(&self) -> Option<IpAddr> {
+        self.online_ip6.filter(|ip| !ip.is_unspecified())
+    }
+}
+
+/// Get the login state of the current device, or of another device if `ip` is specified
+pub async fn get_login_state<T: HttpTransport>(
+    client: &T,
+    portal: &str,
+    ip: Option<IpAddr>,
+    verbose: bool,
+) -> Result<SrunLoginState> {
     // call /rad_user_info with callback=jsonp to get the login state
-    let params = [("callback", "jsonp")];
-    let url = format!("{}/cgi-bin/rad_user_info", SRUN_PORTAL);
+    let ip_str = ip.map(|ip| ip.to_string());
+    let mut params = vec![("callback", "jsonp")];
+    if let Some(ip) = &ip_str {
+        params.push(("ip", ip.as_str()));
+    }
+    let url = format!("{}/cgi-bin/rad_user_info", portal);
 
     // get the response and extract the json
-    let resp = client
-        .get(&url)
-        .query(&params)
-        .send()
+    let raw_text = client
+        .get(&url, &params)
         .await
-        .with_context(|| "failed to get login state")?;
-    let raw_text = resp.text().await?;
+        .with_context(|| "failed to get login state")?
+        .body;
 
     if verbose {
         println!(
@@ -129,46 +161,6 @@ pub async fn get_login_state(client: &Client, verbose: bool) -> Result<SrunLogin
     Ok(parsed_json)
 }
 
-/// Get the ac_id of the current device by visiting a URL
-async fn get_acid_by_url(client: &Client, url: &str) -> Result<String> {
-    let resp = client.get(url).send().await.with_context(|| {
-        format!(
-            "failed to get ac_id from `{}`",
-            url.if_supports_color(Stdout, |t| t.underline())
-        )
-    })?;
-    let redirect_url = resp.url().to_string();
-    let parsed_url = url::Url::parse(&redirect_url).with_context(|| {
-        format!(
-            "failed to parse url `{}`",
-            redirect_url.if_supports_color(Stdout, |t| t.underline())
-        )
-    })?;
-
-    let mut query = parsed_url.query_pairs().into_owned();
-    let ac_id = query.find(|(key, _)| key == "ac_id").with_context(|| {
-        format!(
-            "failed to get ac_id from `{}`",
-            redirect_url.if_supports_color(Stdout, |t| t.underline())
-        )
-    })?;
-    Ok(ac_id.1)
-}
-
-/// Get the ac_id of the current device
-async fn get_acid(client: &Client) -> Result<String> {
-    // Try to visit `CAPTIVE_PORTAL_TEST`.
-    // If not logged in, it will be redirected to `SRUN_PORTAL` with ac_id.
-    // Otherwise, we fall back to visit `SRUN_PORTAL` directly.
-    // https://en.wikipedia.org/wiki/Captive_portal#Detection
-    //
-    // Because of ITC's double authentication mechanism, visiting `SRUN_PORTAL` directly is not preferred.
-    // https://itc.bit.edu.cn/fwzn/zxbl/f2c0c8e939ce4e9cace880d5403fe4b5.htm
-    get_acid_by_url(client, CAPTIVE_PORTAL_TEST)
-        .await
-        .or(get_acid_by_url(client, SRUN_PORTAL).await)
-}
-
 /// SRUN portal response type when calling login/logout
 ///
 /// Note that fields that are not used are omitted
@@ -182,6 +174,10 @@ pub struct SrunPortalResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub suc_msg: Option<String>,
 
+    // present on dual-stack deployments
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub online_ip6: Option<IpAddr>,
+
     // always present on logins and logouts
     pub client_ip: IpAddr,
     pub online_ip: IpAddr,
@@ -196,124 +192,398 @@ pub struct SrunChallenge {
     pub challenge: String,
 }
 
-/// SRUN client
+/// A login request and the intermediate values it is computed from
+#[derive(Debug, Clone)]
+pub struct LoginPayload {
+    /// The challenge from `/get_challenge`
+    pub token: String,
+    /// The JSON encrypted into `info`, including the plaintext password
+    pub info_data: Secret,
+    /// `info_data` encrypted with `xencode` using the token as key
+    pub encoded_data: Vec<u8>,
+    /// `{SRBX1}` followed by `encoded_data` in the custom base64 alphabet
+    pub info: String,
+    /// HMAC-MD5 keyed with the token, sent as the password
+    pub hmd5: String,
+    /// The token-joined string hashed into `chksum`
+    pub chksum_data: String,
+    /// SHA1 of `chksum_data`
+    pub chksum: String,
+    pub url: String,
+    pub params: Vec<(&'static str, String)>,
+}
+
+impl LoginPayload {
+    /// The full login URL, including the query string
+    pub fn full_url(&self) -> Result<String> {
+        let url = Url::parse_with_params(&self.url, &self.params)
+            .with_context(|| format!("failed to parse url `{}`", self.url))?;
+        Ok(url.to_string())
+    }
+
+    /// `info_data` with the password replaced by `[redacted]`, e.g., to be printed
+    pub fn redacted_info_data(&self) -> Result<String> {
+        let mut info_data = serde_json::from_str::<Value>(self.info_data.expose())
+            .with_context(|| "failed to parse info data")?;
+        if let Some(Value::String(password)) = info_data.get_mut("password") {
+            password.zeroize();
+            password.push_str("[redacted]");
+        }
+        Ok(info_data.to_string())
+    }
+}
+
+/// Builder for `SrunClient`
+///
+/// Building the client never touches the network. Unknown `ac_id` and IP addresses are discovered
+/// on first use or with an explicit `SrunClient::discover()`.
+#[derive(Debug, Default)]
+pub struct SrunClientBuilder {
+    portal: Option<String>,
+    username: String,
+    password: Secret,
+    ip: Option<IpAddr>,
+    ip6: Option<IpAddr>,
+    ac_id: Option<String>,
+    dm: bool,
+    double_stack: bool,
+    os: Option<String>,
+    name: Option<String>,
+    strategies: Option<Vec<Strategy>>,
+    scheme: Option<Box<dyn LoginScheme>>,
+    http_client: Option<ReqwestTransport>,
+
+    // only used when building the http client, ignored if one is provided
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) local_address: Option<IpAddr>,
+}
+
+impl SrunClientBuilder {
+    /// The SRUN portal URL, `SRUN_PORTAL` by default
+    pub fn portal(mut self, portal: impl Into<String>) -> Self {
+        self.portal = Some(portal.into());
+        self
+    }
+
+    /// The username of the SRUN account (student id)
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = username.into();
+        self
+    }
+
+    /// The password of the SRUN account, not required for dumb terminals
+    pub fn password(mut self, password: impl Into<Secret>) -> Self {
+        self.password = password.into();
+        self
+    }
+
+    /// The IPv4 address (`online_ip` from the login portal if not specified)
+    pub fn ip(mut self, ip: impl Into<Option<IpAddr>>) -> Self {
+        self.ip = ip.into();
+        self
+    }
+
+    /// The IPv6 address for dual-stack logins, implies `double_stack` if specified
+    ///
+    /// Discovered from `online_ip6` or the local outbound IPv6 address if not specified.
+    pub fn ip6(mut self, ip6: impl Into<Option<IpAddr>>) -> Self {
+        self.ip6 = ip6.into();
+        self
+    }
+
+    /// The ac_id of the current device (discovered through captive portal redirects if not specified)
+    pub fn ac_id(mut self, ac_id: impl Into<Option<String>>) -> Self {
+        self.ac_id = ac_id.into();
+        self
+    }
+
+    /// Whether the device is authenticated through the campus login portal with its mac address
+    /// (important for dumb terminals!!!)
+    pub fn dm(mut self, dm: bool) -> Self {
+        self.dm = dm;
+        self
+    }
+
+    /// Whether to log in with both IPv4 and IPv6 addresses
+    pub fn double_stack(mut self, double_stack: bool) -> Self {
+        self.double_stack = double_stack;
+        self
+    }
+
+    /// The device identity (`os` and `name`) reported to the portal when logging in
+    ///
+    /// Nothing is reported if not specified, which is how the portal counts this device by default.
+    pub fn device(mut self, os: Option<String>, name: Option<String>) -> Self {
+        self.os = os;
+        self.name = name;
+        self
+    }
+
+    /// Captive portal detection strategies to discover the ac_id, `default_strategies` if not
+    /// specified
+    pub fn strategies(mut self, strategies: Vec<Strategy>) -> Self {
+        self.strategies = Some(strategies);
+        self
+    }
+
+    /// How login requests are computed, probed from the portal on login if not specified
+    pub fn scheme(mut self, scheme: impl LoginScheme + 'static) -> Self {
+        self.scheme = Some(Box::new(scheme));
+        self
+    }
+
+    /// Timeout of each request to the portal
+    pub fn timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    /// User agent of requests to the portal
+    pub fn user_agent(mut self, user_agent: impl Into<Option<String>>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Local address to send requests to the portal from
+    pub fn local_address(mut self, local_address: impl Into<Option<IpAddr>>) -> Self {
+        self.local_address = local_address.into();
+        self
+    }
+
+    /// The http client to be reused (a new one will be created if not specified)
+    pub fn http_client(mut self, http_client: impl Into<ReqwestTransport>) -> Self {
+        self.http_client = Some(http_client.into());
+        self
+    }
+
+    /// Build the SRUN client with `reqwest`
+    pub fn build(mut self) -> Result<SrunClient> {
+        let http_client = match self.http_client.take() {
+            Some(http_client) => http_client,
+            None => {
+                let http_client = ReqwestTransport::new(|builder| {
+                    let builder = builder.local_address(self.local_address);
+                    match self.timeout {
+                        Some(timeout) => builder.timeout(timeout),
+                        None => builder,
+                    }
+                })?;
+                match &self.user_agent {
+                    Some(user_agent) => http_client.user_agent(user_agent)?,
+                    None => http_client,
+                }
+            }
+        };
+        Ok(self.build_with(http_client))
+    }
+
+    /// Build the SRUN client with another HTTP transport
+    ///
+    /// Note that `timeout`, `user_agent` and `local_address` are up to the transport itself.
+    pub fn build_with<T: HttpTransport>(self, http_client: T) -> SrunClient<T> {
+        let portal = self.portal.unwrap_or_else(|| SRUN_PORTAL.into());
+        SrunClient {
+            http_client,
+            username: self.username,
+            password: self.password,
+            strategies: self
+                .strategies
+                .unwrap_or_else(|| default_strategies(&portal)),
+            scheme: self.scheme,
+            capabilities: None,
+            portal,
+            ip: self.ip,
+            ip6: self.ip6,
+            ac_id: self.ac_id,
+            dm: self.dm,
+            // an explicitly provided IPv6 address implies dual-stack login
+            double_stack: self.double_stack | self.ip6.is_some(),
+            login_state: None,
+            os: self.os,
+            name: self.name,
+        }
+    }
+}
+
+/// SRUN client, generic over the HTTP transport (`ReqwestTransport` by default)
 #[derive(Debug)]
-pub struct SrunClient {
+pub struct SrunClient<T: HttpTransport = ReqwestTransport> {
     // reusable http client
-    pub http_client: Client,
+    pub http_client: T,
 
     // srun login info, username is student id
     pub username: String,
-    pub password: String,
+    pub password: Secret,
 
-    // srun portal info
-    pub ip: IpAddr,
-    pub ac_id: String,
+    // srun portal info, `ip`, `ip6` and `ac_id` are discovered lazily if not specified
+    pub portal: String,
+    pub ip: Option<IpAddr>,
+    pub ip6: Option<IpAddr>, // only used when logging in through both IPv4 and IPv6
+    pub ac_id: Option<String>,
     pub dm: bool, // whether the device is authenticated with its mac address
-    pub login_state: SrunLoginState,
+    pub double_stack: bool,
+    pub login_state: Option<SrunLoginState>,
+    pub strategies: Vec<Strategy>, // captive portal detection strategies for ac_id discovery
+    pub scheme: Option<Box<dyn LoginScheme>>, // probed from the portal if not specified
+    pub capabilities: Option<Capabilities>,
+
+    // reported device identity, gateways may count device types separately (PC vs mobile)
+    pub os: Option<String>,
+    pub name: Option<String>,
 }
 
 impl SrunClient {
-    /// Create a new SRUN client, where the http client will be reused if provided
+    /// Create a builder for the SRUN client
+    pub fn builder() -> SrunClientBuilder {
+        SrunClientBuilder::default()
+    }
+}
+
+impl<T: HttpTransport> SrunClient<T> {
+    /// Discover the `ac_id` and IP addresses of the current device that are not yet known
     ///
-    /// # Arguments
+    /// Nothing is sent to the portal if they were all specified when building the client.
+    pub async fn discover(&mut self, verbose: bool) -> Result<()> {
+        if self.ac_id.is_none() {
+            self.ac_id = Some(detect_ac_id(&self.http_client, &self.strategies, verbose).await?);
+        }
+
+        let missing_ip6 = self.double_stack & self.ip6.is_none();
+        if self.ip.is_none() | missing_ip6 {
+            let login_state = self.fetch_login_state(false).await?;
+            self.ip = self.ip.or(Some(login_state.online_ip));
+            if missing_ip6 {
+                self.ip6 = login_state.online_ipv6().or_else(get_local_ipv6);
+            }
+        }
+        if self.double_stack & self.ip6.is_none() {
+            bail!("dual-stack login requested, but no IPv6 address found for this device")
+        }
+        Ok(())
+    }
+
+    /// Get the login state of the client's IP address, only fetched from the portal once
+    pub async fn fetch_login_state(&mut self, verbose: bool) -> Result<SrunLoginState> {
+        if let Some(login_state) = &self.login_state {
+            return Ok(login_state.clone());
+        }
+        let login_state =
+            get_login_state(&self.http_client, &self.portal, self.ip, verbose).await?;
+        self.login_state = Some(login_state.clone());
+        Ok(login_state)
+    }
+
+    /// Probe the capabilities of the portal, only probed once
     ///
-    /// * `username` - The username of the SRUN account (student id)
-    /// * `password` - The password of the SRUN account
-    /// * `ip` - The IP address (`online_ip` from the login portal if not specified)
-    /// * `dm` - Whether the device is authenticated through the campus login portal with its mac
-    ///   address (important for dumb terminals!!!)
-    /// * `http_client` - The http client to be used (a new one will be created if not specified)
-    pub async fn new(
-        username: String,
-        password: String,
-        http_client: Option<Client>,
-        ip: Option<IpAddr>,
-        dm: Option<bool>,
-    ) -> Result<SrunClient> {
-        let http_client = http_client.unwrap_or_default();
-        let ac_id = get_acid(&http_client).await?;
-        let login_state = get_login_state(&http_client, false).await?;
-        let ip = ip.unwrap_or(login_state.online_ip);
-        let dm = dm.unwrap_or(false);
-        Ok(SrunClient {
-            http_client,
-            username,
-            password,
+    /// The login scheme is picked from the capabilities if not specified when building the client.
+    pub async fn probe(&mut self, verbose: bool) -> Result<Capabilities> {
+        if let Some(caps) = &self.capabilities {
+            return Ok(caps.clone());
+        }
+        let login_state = self.fetch_login_state(false).await.ok();
+        let caps = probe_capabilities(
+            &self.http_client,
+            &self.portal,
+            login_state.as_ref(),
+            verbose,
+        )
+        .await;
+        if self.scheme.is_none() {
+            self.scheme = Some(Box::new(caps.scheme()));
+        }
+        self.capabilities = Some(caps.clone());
+        Ok(caps)
+    }
+
+    /// The discovered IP address and ac_id
+    fn discovered(&self) -> Result<(IpAddr, String)> {
+        match (self.ip, &self.ac_id) {
+            (Some(ip), Some(ac_id)) => Ok((ip, ac_id.clone())),
+            _ => bail!("ip and ac_id of the current device are not discovered yet"),
+        }
+    }
+
+    /// Compute the login request from a challenge `token`, without sending anything
+    pub fn login_payload(&self, token: &str) -> Result<LoginPayload> {
+        let (ip, ac_id) = self.discovered()?;
+        let input = LoginInput {
+            portal: &self.portal,
+            username: &self.username,
+            password: &self.password,
             ip,
-            ac_id,
-            dm,
-            login_state,
-        })
+            ac_id: &ac_id,
+        };
+        let fallback = SrunScheme::default();
+        let scheme = self.scheme.as_deref().unwrap_or(&fallback);
+        let mut payload = scheme.login_payload(&input, token)?;
+
+        // dual-stack logins authenticate the IPv6 address within the same session
+        if self.double_stack {
+            payload.params.push(("double_stack", "1".to_string()));
+            payload.params.push((
+                "ipv6",
+                self.ip6.map(|ip| ip.to_string()).unwrap_or_default(),
+            ));
+        }
+
+        // device identity as reported by the official portal and clients
+        if let Some(os) = &self.os {
+            payload.params.push(("os", os.clone()));
+        }
+        if let Some(name) = &self.name {
+            payload.params.push(("name", name.clone()));
+        }
+        Ok(payload)
     }
 
     /// Login to the SRUN portal
-    pub async fn login(&self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
+    pub async fn login(&mut self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
+        self.discover(verbose).await?;
+        let (ip, _) = self.discovered()?;
+
         // check if already logged in
-        if (self.login_state.error == "ok") & !force {
-            bail!(
-                "{} already logged in",
-                self.login_state
-                    .online_ip
-                    .to_string()
-                    .if_supports_color(Stdout, |t| t.underline())
-            )
+        if !force {
+            let login_state = self.fetch_login_state(false).await?;
+            if login_state.error == "ok" {
+                bail!(
+                    "{} already logged in",
+                    login_state
+                        .online_ip
+                        .to_string()
+                        .if_supports_color(Stdout, |t| t.underline())
+                )
+            }
         }
 
-        // construct checksum and crypto encodings
-        let token = self.get_challenge(verbose).await?;
-
-        let chksum_data = json!({
-            "username": self.username.clone(),
-            "password": self.password.clone(),
-            "ip": self.ip.to_string(),
-            "acid": self.ac_id.clone(),
-            "enc_ver": String::from("srun_bx1"),
-        });
-
-        let json_chksum_data = serde_json::to_string(&chksum_data)?;
-        let encoded_data = xencode(json_chksum_data.as_str(), token.as_str());
-        let info = format!("{}{}", "{SRBX1}", fkbase64(encoded_data));
-
-        // construct param payload
-        let mac = Hmac::<Md5>::new_from_slice(token.as_bytes())?;
-        let hmd5 = format!("{:x}", mac.finalize().into_bytes());
-
-        let chksum = {
-            let chk = format!(
-                "{0}{1}{0}{2}{0}{3}{0}{4}{0}{5}{0}{6}{0}{7}",
-                &token, &self.username, &hmd5, &self.ac_id, &self.ip, &SRUN_N, &SRUN_TYPE, &info
-            );
-            let mut hasher = Sha1::new();
-            hasher.update(chk);
-            format!("{:x}", hasher.finalize())
-        };
+        // dumb terminals are authenticated with their mac address, no password required, and
+        // `rad_user_dm` only ever drops sessions, so they are checked instead of logged in
+        if self.dm && self.password.is_empty() {
+            return self.dm_check(verbose).await;
+        }
 
-        // construct request body
-        let password_encoded = format!("{}{}", "{MD5}", hmd5);
-        let params = [
-            ("callback", "jsonp"),
-            ("action", "login"),
-            ("username", self.username.as_str()),
-            ("password", password_encoded.as_str()),
-            ("chksum", &chksum.as_str()),
-            ("info", &info.as_str()),
-            ("ac_id", self.ac_id.as_str()),
-            ("ip", &self.ip.to_string()),
-            ("type", SRUN_TYPE),
-            ("n", SRUN_N),
-        ];
-        let url = format!("{}/cgi-bin/srun_portal", SRUN_PORTAL);
+        // pick the login scheme of the portal if not specified
+        if self.scheme.is_none() {
+            self.probe(verbose).await?;
+        }
+
+        // construct checksum and crypto encodings
+        let token = self.get_challenge(ip, verbose).await?;
+        let payload = self.login_payload(&token)?;
+        let params: Vec<(&str, &str)> = payload
+            .params
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect();
+        let url = &payload.url;
 
         // send login request
-        let resp = self
+        let raw_text = self
             .http_client
-            .get(&url)
-            .query(&params)
-            .send()
+            .get(url, &params)
             .await
-            .with_context(|| "failed to send request when logging in")?;
-        let raw_text = resp.text().await?;
+            .with_context(|| "failed to send request when logging in")?
+            .body;
 
         if verbose {
             println!(
@@ -331,20 +601,73 @@ impl SrunClient {
             .with_context(|| format!("failed to parse malformed login response:\n  {}", raw_json))
     }
 
+    /// Complete a second authentication stage after a successful login, if the network has one
+    ///
+    /// Connectivity is re-probed after login. A second login on the SRUN portal with another ac_id
+    /// is completed headlessly, any other web authentication is an error as the device is still
+    /// offline. Connectivity that can't be determined is not an error.
+    pub async fn complete_double_auth(&mut self, verbose: bool) -> Result<()> {
+        let mut connectivity = check_connectivity(&self.http_client).await;
+        if let Connectivity::Intercepted(url) = &connectivity {
+            let ac_id = ac_id_from_url(url);
+            if is_srun_stage(&self.portal, url) && ac_id.is_some() && ac_id != self.ac_id {
+                if verbose {
+                    println!(
+                        "{} second authentication stage at `{}`, logging in with ac_id={}",
+                        "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                        url,
+                        ac_id.clone().unwrap_or_default()
+                    );
+                }
+                // the second stage is only logged in once, later logins go to the first stage
+                let first_stage = std::mem::replace(&mut self.ac_id, ac_id);
+                let resp = self.login(true, verbose).await;
+                self.ac_id = first_stage;
+                let resp = resp?;
+                if resp.error != "ok" {
+                    bail!(
+                        "second authentication stage failed, {} ({})",
+                        resp.error,
+                        resp.error_msg
+                    )
+                }
+                connectivity = check_connectivity(&self.http_client).await;
+            }
+        }
+
+        if verbose {
+            println!(
+                "{} connectivity after login: {}",
+                "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                connectivity
+            );
+        }
+        match connectivity {
+            Connectivity::Intercepted(url) => bail!(
+                "logged in to the portal, but traffic is still intercepted by a second web \
+                authentication at `{}`, complete it in a browser",
+                url
+            ),
+            _ => Ok(()),
+        }
+    }
+
     /// Logout of the SRUN portal
-    pub async fn logout(&self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
+    pub async fn logout(&mut self, force: bool, verbose: bool) -> Result<SrunPortalResponse> {
+        self.discover(verbose).await?;
+        let (ip, ac_id) = self.discovered()?;
+        let login_state = self.fetch_login_state(false).await?;
+
         // check if already logged out
-        if (self.login_state.error == "not_online_error") & !force {
+        if (login_state.error == "not_online_error") & !force {
             bail!(
                 "{} already logged out",
-                self.ip
-                    .to_string()
-                    .if_supports_color(Stdout, |t| t.underline())
+                ip.to_string().if_supports_color(Stdout, |t| t.underline())
             )
         }
 
         // check if username match
-        let logged_in_username = self.login_state.user_name.clone().unwrap_or_default();
+        let logged_in_username = login_state.user_name.clone().unwrap_or_default();
         if logged_in_username != self.username {
             println!(
                 "{} logged in user {} does not match yourself {}, logging out anyway",
@@ -355,68 +678,40 @@ impl SrunClient {
         }
 
         // check if ip match
-        let logged_in_ip = self.login_state.online_ip;
-        if logged_in_ip != self.ip {
+        let logged_in_ip = login_state.online_ip;
+        if logged_in_ip != ip {
             println!(
                 "{} logged in ip (`{}`) does not match `{}`, things may not work as expected",
                 "warning:".if_supports_color(Stdout, |t| t.yellow()),
                 logged_in_ip
                     .to_string()
                     .if_supports_color(Stdout, |t| t.underline()),
-                self.ip
-                    .to_string()
-                    .if_supports_color(Stdout, |t| t.underline())
+                ip.to_string().if_supports_color(Stdout, |t| t.underline())
             );
         }
 
-        // perform logout action
-        let url = {
-            // dumb terminals use a different endpoint (dm logout)
-            match self.dm {
-                true => format!("{}/cgi-bin/rad_user_dm", SRUN_PORTAL),
-                false => format!("{}/cgi-bin/srun_portal", SRUN_PORTAL),
-            }
-        };
-
-        let ip_str = self.ip.to_string();
-        let mut params = vec![
-            ("callback", String::from("jsonp")),
-            ("ip", self.ip.to_string()),
-            ("username", logged_in_username.clone()),
-        ];
-
+        // dumb terminals use a different endpoint (dm logout)
         if self.dm {
-            use chrono::Utc;
-            let timestamp = Utc::now().timestamp().to_string();
-            let unbind = String::from("1");
-
-            let sign = {
-                let mut hasher = Sha1::new();
-                let sn = format!(
-                    "{0}{1}{2}{3}{0}",
-                    timestamp, logged_in_username, ip_str, unbind
-                );
-
-                hasher.update(sn);
-                format!("{:x}", hasher.finalize())
-            };
-
-            params.push(("time", timestamp));
-            params.push(("unbind", unbind));
-            params.push(("sign", sign));
-        } else {
-            params.push(("action", String::from("logout")));
-            params.push(("ac_id", self.ac_id.clone()));
+            return self.dm_logout(&logged_in_username, ip, verbose).await;
         }
 
-        let resp = self
+        // perform logout action
+        let url = format!("{}/cgi-bin/srun_portal", self.portal);
+        let ip_str = ip.to_string();
+        let params = [
+            ("callback", "jsonp"),
+            ("ip", ip_str.as_str()),
+            ("username", logged_in_username.as_str()),
+            ("action", "logout"),
+            ("ac_id", ac_id.as_str()),
+        ];
+
+        let raw_text = self
             .http_client
-            .get(&url)
-            .query(&params)
-            .send()
+            .get(&url, &params)
             .await
-            .with_context(|| "failed to send request when logging out")?;
-        let raw_text = resp.text().await?;
+            .with_context(|| "failed to send request when logging out")?
+            .body;
 
         if verbose {
             println!(
@@ -434,22 +729,113 @@ impl SrunClient {
             .with_context(|| format!("failed to parse malformed logout response:\n  {}", raw_json))
     }
 
-    async fn get_challenge(&self, verbose: bool) -> Result<String> {
+    /// Check that a dumb terminal is online through its mac address authentication
+    ///
+    /// Nothing is sent to log it in, a dropped mac authentication is an error instead, as it can
+    /// only be restored by a portal login with a password (or through the web portal). The login
+    /// state is always fetched again, as long-lived clients (e.g., keep-alive) check it repeatedly.
+    async fn dm_check(&mut self, verbose: bool) -> Result<SrunPortalResponse> {
+        let login_state =
+            get_login_state(&self.http_client, &self.portal, self.ip, verbose).await?;
+        self.login_state = Some(login_state.clone());
+        if login_state.error != "ok" {
+            bail!(
+                "dumb terminal {} is not online ({}), its mac address authentication may have \
+                been dropped, log in once with a password to restore it",
+                login_state
+                    .online_ip
+                    .to_string()
+                    .if_supports_color(Stdout, |t| t.underline()),
+                login_state.error
+            )
+        }
+        Ok(SrunPortalResponse {
+            access_token: None,
+            username: login_state.user_name.clone(),
+            suc_msg: Some("authenticated with mac address".into()),
+            online_ip6: login_state.online_ip6,
+            client_ip: login_state.online_ip,
+            online_ip: login_state.online_ip,
+            error: login_state.error,
+            error_msg: String::new(),
+            res: "ok".into(),
+        })
+    }
+
+    /// Log out a registered dumb terminal through the `rad_user_dm` disconnect endpoint
+    ///
+    /// Instead of a password, requests are signed with `sha1(time + username + ip + unbind + time)`.
+    /// `unbind` is always set, which drops the mac address authentication as well.
+    async fn dm_logout(
+        &self,
+        username: &str,
+        ip: IpAddr,
+        verbose: bool,
+    ) -> Result<SrunPortalResponse> {
+        let timestamp = Utc::now().timestamp().to_string();
+        let unbind = "1";
+        let ip_str = ip.to_string();
+
+        let sign = {
+            let mut hasher = Sha1::new();
+            let sn = format!("{0}{1}{2}{3}{0}", timestamp, username, ip_str, unbind);
+            hasher.update(sn);
+            format!("{:x}", hasher.finalize())
+        };
+
+        let params = [
+            ("callback", "jsonp"),
+            ("ip", ip_str.as_str()),
+            ("username", username),
+            ("time", timestamp.as_str()),
+            ("unbind", unbind),
+            ("sign", sign.as_str()),
+        ];
+        let url = format!("{}/cgi-bin/rad_user_dm", self.portal);
+
+        let raw_text = self
+            .http_client
+            .get(&url, &params)
+            .await
+            .with_context(|| "failed to send request on dm logout")?
+            .body;
+
+        if verbose {
+            println!(
+                "{} dm logout response from portal:\n{}",
+                "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                raw_text.if_supports_color(Stdout, |t| t.dimmed())
+            );
+        }
+
+        if raw_text.len() < 8 {
+            bail!("dm logout response too short: `{}`", raw_text)
+        }
+        let raw_json = &raw_text[6..raw_text.len() - 1];
+        serde_json::from_str::<SrunPortalResponse>(raw_json).with_context(|| {
+            format!(
+                "failed to parse malformed dm logout response:\n  {}",
+                raw_json
+            )
+        })
+    }
+
+    /// Request a challenge (the token used to encrypt login payloads) for `ip`
+    pub async fn get_challenge(&self, ip: IpAddr, verbose: bool) -> Result<String> {
+        let ip_str = ip.to_string();
         let params = [
             ("callback", "jsonp"),
             ("username", self.username.as_str()),
-            ("ip", &self.ip.to_string()),
+            ("ip", ip_str.as_str()),
         ];
-        let url = format!("{}/cgi-bin/get_challenge", SRUN_PORTAL);
+        let url = format!("{}/cgi-bin/get_challenge", self.portal);
 
-        let resp = self
+        let raw_text = self
             .http_client
-            .get(&url)
-            .query(&params)
-            .send()
+            .get(&url, &params)
             .await
-            .with_context(|| "failed to get challenge")?;
-        let raw_text = resp.text().await?;
+            .with_context(|| "failed to get challenge")?
+            .body;
 
         if verbose {
             println!(
@@ -472,3 +858,152 @@ impl SrunClient {
         Ok(parsed_json.challenge)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ScriptedTransport;
+
+    const ONLINE: &str = r#"jsonp({"error":"ok","online_ip":"10.62.1.2","client_ip":"10.62.1.2","user_name":"1120201234","online_ip6":"::"})"#;
+    const OFFLINE: &str =
+        r#"jsonp({"error":"not_online_error","online_ip":"10.62.1.2","client_ip":"10.62.1.2"})"#;
+
+    fn client(transport: ScriptedTransport) -> SrunClient<ScriptedTransport> {
+        SrunClient::builder()
+            .username("1120201234")
+            .password("hunter2")
+            .ip("10.62.1.2".parse::<IpAddr>().unwrap())
+            .ac_id("1".to_string())
+            .scheme(SrunScheme::default())
+            .build_with(transport)
+    }
+
+    fn param(request: &str, key: &str) -> Option<String> {
+        Url::parse(request)
+            .unwrap()
+            .query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    }
+
+    #[tokio::test]
+    async fn get_login_state_parses_jsonp() {
+        let transport = ScriptedTransport::new().respond(SRUN_PORTAL, ONLINE);
+        let ip = "10.62.1.2".parse().unwrap();
+        let state = get_login_state(&transport, SRUN_PORTAL, Some(ip), false)
+            .await
+            .unwrap();
+        assert_eq!(state.error, "ok");
+        assert_eq!(state.online_ip, ip);
+        assert_eq!(state.user_name.as_deref(), Some("1120201234"));
+        assert_eq!(state.online_ipv6(), None);
+        assert_eq!(
+            transport.requests(),
+            ["http://10.0.0.55/cgi-bin/rad_user_info?callback=jsonp&ip=10.62.1.2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn get_login_state_rejects_short_responses() {
+        let transport = ScriptedTransport::new().respond(SRUN_PORTAL, "jsonp()");
+        assert!(get_login_state(&transport, SRUN_PORTAL, None, false)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn login_sends_encrypted_payload() {
+        let transport = ScriptedTransport::new()
+            .respond(SRUN_PORTAL, OFFLINE)
+            .respond(SRUN_PORTAL, r#"jsonp({"challenge":"8f1c2d3e4b5a6978"})"#)
+            .respond(
+                SRUN_PORTAL,
+                r#"jsonp({"client_ip":"10.62.1.2","online_ip":"10.62.1.2","error":"ok","error_msg":"","res":"ok","suc_msg":"login_ok"})"#,
+            );
+        let mut client = client(transport);
+        let resp = client.login(false, false).await.unwrap();
+        assert_eq!(resp.error, "ok");
+        assert_eq!(resp.suc_msg.as_deref(), Some("login_ok"));
+
+        let requests = client.http_client.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(param(&requests[1], "username").unwrap(), "1120201234");
+        assert_eq!(param(&requests[1], "ip").unwrap(), "10.62.1.2");
+
+        // the payload is computed from the challenge, and never contains the plaintext password
+        let payload = client.login_payload("8f1c2d3e4b5a6978").unwrap();
+        let login = &requests[2];
+        assert_eq!(login, &payload.full_url().unwrap());
+        assert!(login.starts_with("http://10.0.0.55/cgi-bin/srun_portal?"));
+        assert_eq!(param(login, "action").unwrap(), "login");
+        assert_eq!(param(login, "ac_id").unwrap(), "1");
+        assert_eq!(
+            param(login, "password").unwrap(),
+            format!("{{MD5}}{}", payload.hmd5)
+        );
+        assert_eq!(param(login, "chksum").unwrap(), payload.chksum);
+        assert!(param(login, "info").unwrap().starts_with("{SRBX1}"));
+        assert!(!login.contains("hunter2"));
+        assert_eq!(
+            payload.redacted_info_data().unwrap(),
+            r#"{"acid":"1","enc_ver":"srun_bx1","ip":"10.62.1.2","password":"[redacted]","username":"1120201234"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn login_bails_if_already_logged_in() {
+        let transport = ScriptedTransport::new().respond(SRUN_PORTAL, ONLINE);
+        let mut client = client(transport);
+        let err = client.login(false, false).await.unwrap_err();
+        assert!(err.to_string().contains("already logged in"));
+        // nothing is sent after the login state
+        assert_eq!(client.http_client.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dm_login_checks_a_fresh_login_state() {
+        let transport = ScriptedTransport::new()
+            .respond(SRUN_PORTAL, ONLINE)
+            .respond(SRUN_PORTAL, OFFLINE);
+        let mut client = SrunClient::builder()
+            .username("1120201234")
+            .ip("10.62.1.2".parse::<IpAddr>().unwrap())
+            .ac_id("1".to_string())
+            .dm(true)
+            .build_with(transport);
+
+        // keep-alive reuses the client, a dropped mac authentication must be noticed
+        let resp = client.login(true, false).await.unwrap();
+        assert_eq!(resp.error, "ok");
+        let err = client.login(true, false).await.unwrap_err();
+        assert!(err.to_string().contains("is not online"));
+        assert!(client
+            .http_client
+            .requests()
+            .iter()
+            .all(|request| request.contains("/cgi-bin/rad_user_info")));
+    }
+
+    #[tokio::test]
+    async fn logout_sends_logged_in_username() {
+        let transport = ScriptedTransport::new().respond(SRUN_PORTAL, ONLINE).respond(
+            SRUN_PORTAL,
+            r#"jsonp({"client_ip":"10.62.1.2","online_ip":"10.62.1.2","error":"ok","error_msg":"","res":"ok"})"#,
+        );
+        let mut client = client(transport);
+        let resp = client.logout(false, false).await.unwrap();
+        assert_eq!(resp.error, "ok");
+        assert_eq!(
+            client.http_client.requests()[1],
+            "http://10.0.0.55/cgi-bin/srun_portal?callback=jsonp&ip=10.62.1.2&username=1120201234&action=logout&ac_id=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn logout_bails_if_already_logged_out() {
+        let transport = ScriptedTransport::new().respond(SRUN_PORTAL, OFFLINE);
+        let mut client = client(transport);
+        let err = client.logout(false, false).await.unwrap_err();
+        assert!(err.to_string().contains("already logged out"));
+    }
+}
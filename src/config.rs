@@ -1,79 +1,354 @@
 use std::env;
+use std::fmt;
 use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Error;
 use anyhow::Result;
+use directories::BaseDirs;
+use directories::ProjectDirs;
 use owo_colors::OwoColorize;
 use owo_colors::Stream::Stdout;
 
-/// Enumerate possible paths to user config file (platform specific)
+/// Name of the user config file
+const CONFIG_FILE: &str = "bit-user.json";
+
+/// Enumerate possible paths to user config file (platform specific), in order of priority
 ///
 /// On Windows:
+/// * `~\AppData\Roaming\bitsrun\config\bit-user.json`
 /// * `~\AppData\Roaming\bitsrun\bit-user.json`
 ///
 /// On Linux:
-/// * `$XDG_CONFIG_HOME/bitsrun/bit-user.json`
-/// * `~/.config/bitsrun/bit-user.json`
+/// * `$XDG_CONFIG_HOME/bitsrun/bit-user.json` (`~/.config/bitsrun/bit-user.json` by default)
 /// * `~/.config/bit-user.json`
+/// * `$XDG_CONFIG_DIRS/bitsrun/bit-user.json` (`/etc/xdg/bitsrun/bit-user.json` by default)
+/// * `/etc/bitsrun/bit-user.json`
 ///
 /// On macOS:
-/// * `$HOME/Library/Preferences/bitsrun/bit-user.json`
-/// * `$HOME/.config/bit-user.json`
-/// * `$HOME/.config/bitsrun/bit-user.json`
+/// * `~/Library/Application Support/bitsrun/bit-user.json`
+/// * `$XDG_CONFIG_HOME/bitsrun/bit-user.json` (if set)
+/// * `~/.config/bitsrun/bit-user.json`
+/// * `~/.config/bit-user.json`
+/// * `~/Library/Preferences/bitsrun/bit-user.json`
+/// * `$XDG_CONFIG_DIRS/bitsrun/bit-user.json` (`/etc/xdg/bitsrun/bit-user.json` by default)
+/// * `/etc/bitsrun/bit-user.json`
 ///
 /// Additionally, `bitsrun` will search for config file in the current working directory.
 pub fn enumerate_config_paths() -> Vec<String> {
-    let mut paths = Vec::new();
+    let mut paths: Vec<PathBuf> = Vec::new();
+
+    // platform config directory, respects `$XDG_CONFIG_HOME` on Linux
+    if let Some(dirs) = ProjectDirs::from("", "", "bitsrun") {
+        paths.push(dirs.config_dir().join(CONFIG_FILE));
+    }
+
+    // XDG user config directory on other unix systems, relative paths are ignored as per the spec
+    if let Some(config_home) = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        if cfg!(unix) && config_home.is_absolute() {
+            paths.push(config_home.join("bitsrun").join(CONFIG_FILE));
+        }
+    }
 
-    // Windows
+    // locations searched by earlier releases
     if env::consts::OS == "windows" {
         if let Some(appdata) = env::var_os("APPDATA") {
-            paths.push(format!(
-                "{}\\bitsrun\\bit-user.json",
-                appdata.to_str().unwrap()
-            ));
+            paths.push(PathBuf::from(appdata).join("bitsrun").join(CONFIG_FILE));
         }
     }
-
-    // Linux (and macOS)
-    if let Some(home) = env::var_os("XDG_CONFIG_HOME").or_else(|| env::var_os("HOME")) {
-        paths.push(format!("{}/.config/bit-user.json", home.to_str().unwrap()));
-        paths.push(format!(
-            "{}/.config/bitsrun/bit-user.json",
-            home.to_str().unwrap()
-        ));
+    if let Some(dirs) = BaseDirs::new() {
+        let home = dirs.home_dir();
+        if cfg!(unix) {
+            paths.push(home.join(".config").join("bitsrun").join(CONFIG_FILE));
+            paths.push(home.join(".config").join(CONFIG_FILE));
+        }
+        if env::consts::OS == "macos" {
+            paths.push(home.join("Library/Preferences/bitsrun").join(CONFIG_FILE));
+        }
     }
 
-    // macOS
-    if env::consts::OS == "macos" {
-        if let Some(home) = env::var_os("HOME") {
-            paths.push(format!(
-                "{}/Library/Preferences/bitsrun/bit-user.json",
-                home.to_str().unwrap()
-            ));
+    // system-wide config directories
+    if cfg!(unix) {
+        let config_dirs = env::var("XDG_CONFIG_DIRS")
+            .ok()
+            .filter(|dirs| !dirs.is_empty())
+            .unwrap_or_else(|| "/etc/xdg".into());
+        for dir in config_dirs.split(':').map(Path::new) {
+            if dir.is_absolute() {
+                paths.push(dir.join("bitsrun").join(CONFIG_FILE));
+            }
         }
+        paths.push(Path::new("/etc/bitsrun").join(CONFIG_FILE));
     }
 
     // current working directory
-    paths.push("bit-user.json".into());
+    paths.push(CONFIG_FILE.into());
+
+    let mut unique: Vec<String> = Vec::new();
+    for path in paths {
+        let path = path.to_string_lossy().into_owned();
+        if !unique.contains(&path) {
+            unique.push(path);
+        }
+    }
+    unique
+}
+
+/// Read config files even if they fail security checks, set once from `--insecure`
+static INSECURE: AtomicBool = AtomicBool::new(false);
+
+/// Read config files even if they fail security checks, with a warning (not recommended)
+pub fn allow_insecure(insecure: bool) {
+    INSECURE.store(insecure, Ordering::Relaxed);
+}
+
+/// Why a file is not safe to read credentials from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Insecurity {
+    /// The file is a symlink owned by another user than the current one or root
+    SymlinkOwner(u32),
+    /// The file is owned by another user than the current one
+    Owner { uid: u32, euid: u32 },
+    /// The file has group or other permissions
+    Permissions(u32),
+    /// The directory of the file is owned by another user than the current one or root
+    DirOwner(PathBuf, u32),
+    /// The directory of the file is writable by other users, without the sticky bit
+    DirWritable(PathBuf, u32),
+}
+
+impl fmt::Display for Insecurity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Insecurity::SymlinkOwner(uid) => {
+                write!(f, "is a symlink owned by another user (uid {})", uid)
+            }
+            Insecurity::Owner { uid, euid } => write!(
+                f,
+                "is owned by another user (uid {}, expected {})",
+                uid, euid
+            ),
+            Insecurity::Permissions(mode) => write!(f, "has too open permissions {:o}", mode),
+            Insecurity::DirOwner(dir, uid) => write!(
+                f,
+                "is in directory `{}` owned by another user (uid {})",
+                dir.display(),
+                uid
+            ),
+            Insecurity::DirWritable(dir, mode) => write!(
+                f,
+                "is in directory `{}` writable by other users ({:o})",
+                dir.display(),
+                mode
+            ),
+        }
+    }
+}
+
+impl Insecurity {
+    /// How to fix it, if it can be fixed by the current user
+    fn tip(&self, path: &str) -> Option<String> {
+        match self {
+            Insecurity::Owner { .. } => {
+                Some(format!("take ownership with `chown $(id -u) {}`", path))
+            }
+            Insecurity::Permissions(_) => Some(format!(
+                "set permissions to {} with `chmod 600 {}`",
+                "600".if_supports_color(Stdout, |t| t.on_cyan()),
+                path
+            )),
+            Insecurity::DirWritable(dir, _) => Some(format!(
+                "remove write permissions with `chmod go-w {}`",
+                dir.display()
+            )),
+            Insecurity::SymlinkOwner(_) | Insecurity::DirOwner(..) => None,
+        }
+    }
+}
+
+/// Find out if a file is unsafe to read credentials from
+///
+/// * The file is owned by the current (effective) user, and has no group or other permissions
+///   (e.g., 0600 or 0400)
+/// * Its directory is owned by the current user or root, and not writable by other users, unless
+///   the sticky bit is set (e.g., `/tmp`)
+/// * Symlinks are only followed if they are owned by the current user or root, and the target is
+///   checked instead
+#[cfg(unix)]
+pub fn find_insecurity(path: &str) -> Result<Option<Insecurity>> {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: `geteuid` has no preconditions and always succeeds
+    let euid = unsafe { libc::geteuid() };
+    let trusted = |uid: u32| uid == euid || uid == 0;
+
+    let link = fs::symlink_metadata(path)?;
+    let path = match link.file_type().is_symlink() {
+        true if !trusted(link.uid()) => return Ok(Some(Insecurity::SymlinkOwner(link.uid()))),
+        true => fs::canonicalize(path)?,
+        false => Path::new(path).to_path_buf(),
+    };
+
+    let meta = fs::metadata(&path)?;
+    if meta.uid() != euid {
+        return Ok(Some(Insecurity::Owner {
+            uid: meta.uid(),
+            euid,
+        }));
+    }
+    if meta.mode() & 0o077 != 0 {
+        return Ok(Some(Insecurity::Permissions(meta.mode() & 0o777)));
+    }
+
+    // anyone who can write to the directory can replace the file
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let dir_meta = fs::metadata(&dir)?;
+    let sticky = dir_meta.mode() & 0o1000 != 0;
+    if !trusted(dir_meta.uid()) {
+        return Ok(Some(Insecurity::DirOwner(dir, dir_meta.uid())));
+    }
+    if dir_meta.mode() & 0o022 != 0 && !sticky {
+        return Ok(Some(Insecurity::DirWritable(dir, dir_meta.mode() & 0o777)));
+    }
+    Ok(None)
+}
+
+/// Windows doesn't support Unix-style permissions, so files are always considered secure
+#[cfg(windows)]
+pub fn find_insecurity(_path: &str) -> Result<Option<Insecurity>> {
+    Ok(None)
+}
+
+/// Check that a file is safe to read credentials from, see `find_insecurity`
+pub fn check_secure_file(path: &str) -> Result<()> {
+    if let Some(insecurity) = find_insecurity(path)? {
+        let tip = match insecurity.tip(path) {
+            Some(tip) => format!(
+                "\n{}: {}",
+                "tip".if_supports_color(Stdout, |t| t.green()),
+                tip
+            ),
+            None => String::new(),
+        };
+        bail!(
+            "`{}` {}, aborting!{}",
+            path.if_supports_color(Stdout, |t| t.underline()),
+            insecurity.if_supports_color(Stdout, |t| t.red()),
+            tip
+        )
+    }
+    Ok(())
+}
+
+/// Status of a possible config path
+#[derive(Debug, Clone)]
+pub enum ConfigStatus {
+    /// The config file that is read
+    Selected,
+    /// A valid config file, but of lower priority than the selected one
+    Shadowed,
+    Missing,
+    NotAFile,
+    /// Skipped because it is unsafe to read credentials from
+    Insecure(Insecurity),
+    /// Skipped because its metadata can't be read
+    Unreadable(String),
+}
+
+impl fmt::Display for ConfigStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigStatus::Selected => write!(f, "selected"),
+            ConfigStatus::Shadowed => write!(f, "found, lower priority"),
+            ConfigStatus::Missing => write!(f, "missing"),
+            ConfigStatus::NotAFile => write!(f, "skipped, not a file"),
+            ConfigStatus::Insecure(insecurity) => write!(f, "skipped, {}", insecurity),
+            ConfigStatus::Unreadable(e) => write!(f, "skipped, {}", e),
+        }
+    }
+}
+
+/// Status of all possible config paths in order of priority, the first valid one is selected
+///
+/// Insecure config files are only selected with `--insecure`.
+pub fn discover_config_paths() -> Vec<(String, ConfigStatus)> {
+    let insecure = INSECURE.load(Ordering::Relaxed);
+    let mut selected = false;
+    let mut paths = Vec::new();
+    for path in enumerate_config_paths() {
+        let status = match fs::metadata(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ConfigStatus::Missing,
+            Err(e) => ConfigStatus::Unreadable(e.to_string()),
+            Ok(meta) if !meta.is_file() => ConfigStatus::NotAFile,
+            Ok(_) => match find_insecurity(&path) {
+                Err(e) => ConfigStatus::Unreadable(e.to_string()),
+                Ok(Some(insecurity)) if !insecure => ConfigStatus::Insecure(insecurity),
+                Ok(_) if selected => ConfigStatus::Shadowed,
+                Ok(_) => {
+                    selected = true;
+                    ConfigStatus::Selected
+                }
+            },
+        };
+        paths.push((path, status));
+    }
     paths
 }
 
 /// Config file validation
+///
+/// Without an explicit path, the first valid config file found is selected. If none is valid,
+/// the first one that was skipped is reported.
 pub fn validate_config_file(config_path: &Option<String>) -> Result<String, Error> {
-    let mut validated_config_path = String::new();
-    match &config_path {
-        Some(path) => validated_config_path = path.to_owned(),
+    let validated_config_path = match &config_path {
+        Some(path) => path.to_owned(),
         None => {
-            for path in enumerate_config_paths() {
-                if fs::metadata(&path).is_ok() {
-                    validated_config_path = path;
-                    break;
+            let paths = discover_config_paths();
+            let selected = paths
+                .iter()
+                .find(|(_, status)| matches!(status, ConfigStatus::Selected))
+                .or_else(|| {
+                    paths.iter().find(|(_, status)| {
+                        !matches!(status, ConfigStatus::Missing | ConfigStatus::Shadowed)
+                    })
+                });
+            match selected {
+                Some((path, ConfigStatus::Selected)) => {
+                    // config files that were meant to be read, but are unsafe
+                    for (skipped, status) in &paths {
+                        if skipped == path {
+                            break;
+                        }
+                        if let ConfigStatus::Insecure(insecurity) = status {
+                            println!(
+                                "{} skipping `{}`, which {}",
+                                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                                skipped.if_supports_color(Stdout, |t| t.underline()),
+                                insecurity
+                            );
+                        }
+                    }
+                    path.clone()
+                }
+                Some((path, _)) => path.clone(),
+                None => {
+                    return Err(anyhow!(
+                        "file `{}` not found, available paths can be found with `{}`",
+                        CONFIG_FILE.if_supports_color(Stdout, |t| t.underline()),
+                        "bitsrun config-paths".if_supports_color(Stdout, |t| t.cyan())
+                    ))
                 }
             }
         }
-    }
+    };
     let meta = fs::metadata(&validated_config_path)?;
     if !meta.is_file() {
         return Err(anyhow!(
@@ -81,39 +356,133 @@ pub fn validate_config_file(config_path: &Option<String>) -> Result<String, Erro
             &validated_config_path.if_supports_color(Stdout, |t| t.underline())
         ));
     }
-    // file should only be read/writeable by the owner alone, i.e., 0o600
-    // note: this check is only performed on unix systems
-    #[cfg(unix)]
-    fn check_permissions(config: &String, meta: &std::fs::Metadata) -> Result<(), anyhow::Error> {
-        use std::os::unix::fs::MetadataExt;
-        if meta.mode() & 0o777 != 0o600 {
-            return Err(anyhow!(
-                "`{}` has too open permissions {}, aborting!\n\
-                {}: set permissions to {} with `chmod 600 {}`",
-                &config.if_supports_color(Stdout, |t| t.underline()),
-                (meta.mode() & 0o777)
-                    .to_string()
-                    .if_supports_color(Stdout, |t| t.on_red()),
-                "tip".if_supports_color(Stdout, |t| t.green()),
-                "600".if_supports_color(Stdout, |t| t.on_cyan()),
-                &config
-            ));
+    if let Err(e) = check_secure_file(&validated_config_path) {
+        if !INSECURE.load(Ordering::Relaxed) {
+            return Err(e);
+        }
+        println!(
+            "{} {}\n{} reading it anyway because of `--insecure`, other users may read or replace \
+            your credentials!",
+            "warning:".if_supports_color(Stdout, |t| t.on_red()),
+            e,
+            "warning:".if_supports_color(Stdout, |t| t.on_red()),
+        );
+    }
+    Ok(validated_config_path)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::lchown;
+    use std::os::unix::fs::symlink;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A scratch directory owned by the current user, removed on drop
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("bitsrun-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            chmod(&dir, 0o700);
+            Scratch(dir)
+        }
+
+        /// Create a file with the given mode, returning its path
+        fn file(&self, name: &str, mode: u32) -> String {
+            let path = self.0.join(name);
+            fs::write(&path, "{}").unwrap();
+            chmod(&path, mode);
+            path.to_str().unwrap().to_string()
         }
-        Ok(())
     }
-    #[cfg(windows)]
-    #[allow(unused)]
-    fn check_permissions(_config: &str, _meta: &std::fs::Metadata) -> Result<(), anyhow::Error> {
-        // Windows doesn't support Unix-style permissions, so we'll just return Ok here.
-        Ok(())
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
     }
-    check_permissions(&validated_config_path, &meta)?;
-    if validated_config_path.is_empty() {
-        return Err(anyhow!(
-            "file `{}` not found, available paths can be found with `{}`",
-            "bit-user.json".if_supports_color(Stdout, |t| t.underline()),
-            "bitsrun config-paths".if_supports_color(Stdout, |t| t.cyan())
-        ));
+
+    fn chmod(path: &Path, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn is_root() -> bool {
+        // SAFETY: `geteuid` has no preconditions and always succeeds
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[test]
+    fn file_permissions() {
+        let scratch = Scratch::new("permissions");
+        for (mode, insecurity) in [
+            (0o600, None),
+            (0o400, None),
+            (0o640, Some(Insecurity::Permissions(0o640))),
+            (0o644, Some(Insecurity::Permissions(0o644))),
+        ] {
+            let path = scratch.file(&format!("{:o}.json", mode), mode);
+            assert_eq!(find_insecurity(&path).unwrap(), insecurity, "{:o}", mode);
+        }
+        assert!(check_secure_file(&scratch.file("644.json", 0o644)).is_err());
+        assert!(check_secure_file(&scratch.file("600.json", 0o600)).is_ok());
+    }
+
+    #[test]
+    fn writable_directories() {
+        let scratch = Scratch::new("directories");
+        let path = scratch.file("bit-user.json", 0o600);
+        for (mode, writable) in [
+            (0o700, false),
+            (0o770, true),
+            (0o777, true),
+            // like `/tmp`, other users can't replace files they don't own
+            (0o1777, false),
+        ] {
+            chmod(&scratch.0, mode);
+            let insecurity =
+                writable.then(|| Insecurity::DirWritable(scratch.0.clone(), mode & 0o777));
+            assert_eq!(find_insecurity(&path).unwrap(), insecurity, "{:o}", mode);
+        }
+    }
+
+    #[test]
+    fn symlinks() {
+        let scratch = Scratch::new("symlinks");
+        let target = scratch.file("target.json", 0o600);
+        let link = scratch.0.join("link.json");
+        symlink(&target, &link).unwrap();
+        let link = link.to_str().unwrap();
+        assert_eq!(find_insecurity(link).unwrap(), None);
+
+        // the target is checked instead of the symlink itself
+        chmod(Path::new(&target), 0o644);
+        assert_eq!(
+            find_insecurity(link).unwrap(),
+            Some(Insecurity::Permissions(0o644))
+        );
+        chmod(Path::new(&target), 0o600);
+
+        // only root can give away a symlink
+        if !is_root() {
+            return;
+        }
+        let nobody = 65534;
+        lchown(link, Some(nobody), None).unwrap();
+        assert_eq!(
+            find_insecurity(link).unwrap(),
+            Some(Insecurity::SymlinkOwner(nobody))
+        );
+        lchown(&target, Some(nobody), None).unwrap();
+        lchown(link, Some(0), None).unwrap();
+        assert_eq!(
+            find_insecurity(link).unwrap(),
+            Some(Insecurity::Owner {
+                uid: nobody,
+                euid: 0
+            })
+        );
     }
-    Ok(validated_config_path)
 }
@@ -1,7 +1,13 @@
+use crate::cache;
 use crate::client::SrunClient;
+use crate::client::SRUN_PORTAL;
 use crate::config;
+use crate::net;
+use crate::scheme::SrunScheme;
+use crate::secret::Secret;
 
 use std::fs;
+use std::net::IpAddr;
 
 use anyhow::Context;
 use anyhow::Result;
@@ -10,8 +16,8 @@ use log::warn;
 use owo_colors::OwoColorize;
 use owo_colors::Stream::Stdout;
 
-use reqwest::Client;
 use serde::Deserialize;
+use zeroize::Zeroizing;
 
 use tokio::signal::ctrl_c;
 use tokio::time::Duration;
@@ -19,10 +25,25 @@ use tokio::time::Duration;
 #[derive(Debug, Deserialize)]
 pub struct SrunDaemon {
     username: String,
-    password: String,
+    // not required for registered dumb terminals
+    #[serde(default)]
+    password: Secret,
     dm: bool,
+    // keeps both IPv4 and IPv6 sessions alive if enabled
+    #[serde(default)]
+    double_stack: bool,
     // polls every 1 hour by default
     poll_interval: Option<u64>,
+    // network interface or local address to bind portal requests to
+    interface: Option<String>,
+    source_ip: Option<IpAddr>,
+    // device identity reported to the portal
+    os: Option<String>,
+    name: Option<String>,
+    // ac_id of the current network, discovered (and cached) if not specified
+    ac_id: Option<String>,
+    // login scheme of the portal, probed if not specified
+    scheme: Option<SrunScheme>,
 }
 
 impl SrunDaemon {
@@ -31,12 +52,14 @@ impl SrunDaemon {
 
         // in daemon mode, bitsrun must be able to read all required fields from the config file,
         // including `username`, `password`, and `dm`.
-        let daemon_cfg_str = fs::read_to_string(&finalized_cfg).with_context(|| {
-            format!(
-                "failed to read config file `{}`",
-                &finalized_cfg.if_supports_color(Stdout, |t| t.underline())
-            )
-        })?;
+        let daemon_cfg_str = fs::read_to_string(&finalized_cfg)
+            .map(Zeroizing::new)
+            .with_context(|| {
+                format!(
+                    "failed to read config file `{}`",
+                    &finalized_cfg.if_supports_color(Stdout, |t| t.underline())
+                )
+            })?;
         let daemon_cfg =
             serde_json::from_str::<SrunDaemon>(&daemon_cfg_str).with_context(|| {
                 format!(
@@ -48,7 +71,10 @@ impl SrunDaemon {
         Ok(daemon_cfg)
     }
 
-    pub async fn start(&self, http_client: Client) -> Result<()> {
+    /// Start the daemon, where `source_ip` overrides `interface` and `source_ip` in the config file
+    ///
+    /// The cached ac_id of the current network is ignored if `refresh_ac_id` is set.
+    pub async fn start(&self, source_ip: Option<IpAddr>, refresh_ac_id: bool) -> Result<()> {
         // set logger to INFO level by default
         pretty_env_logger::formatted_builder()
             .filter_level(log::LevelFilter::Info)
@@ -62,21 +88,61 @@ impl SrunDaemon {
             warn!("polling interval is too short, please set it to at least 10 minutes (600s)");
         }
 
-        // start daemon
+        // bind requests to a local address if specified
+        let source_ip = match source_ip {
+            Some(ip) => Some(ip),
+            None => net::resolve_source_ip(&self.interface, &self.source_ip)?,
+        };
+
+        // fall back to the cached ac_id of the current network, which also works when online
+        let network = cache::network_key(SRUN_PORTAL, source_ip);
+        let cached_ac_id = match refresh_ac_id {
+            true => None,
+            false => network.as_deref().and_then(cache::load_ac_id),
+        };
+        let mut from_cache = self.ac_id.is_none() && cached_ac_id.is_some();
+        let ac_id = self.ac_id.clone().or(cached_ac_id);
+        let mut discover_ac_id = ac_id.is_none();
+
+        // start daemon, discovering the portal info once to fail early
         let mut srun_ticker = tokio::time::interval(Duration::from_secs(poll_interval));
-        let srun = SrunClient::new(
-            self.username.clone(),
-            self.password.clone(),
-            Some(http_client),
-            None,
-            Some(self.dm),
-        )
-        .await?;
+        let mut builder = SrunClient::builder()
+            .username(self.username.clone())
+            .password(self.password.clone())
+            .local_address(source_ip)
+            .ip(source_ip.filter(|ip| ip.is_ipv4()))
+            .ip6(source_ip.filter(|ip| ip.is_ipv6() && self.double_stack))
+            .ac_id(ac_id)
+            .dm(self.dm)
+            .double_stack(self.double_stack)
+            .device(self.os.clone(), self.name.clone());
+        if let Some(scheme) = &self.scheme {
+            builder = builder.scheme(scheme.clone());
+        }
+        let mut srun = builder.build()?;
+        srun.discover(false).await?;
+        // pick the login scheme of the portal once, unless configured or never logging in with it
+        let logs_in = !self.dm || !self.password.is_empty();
+        if logs_in && self.scheme.is_none() {
+            if let Some(warning) = srun.probe(false).await?.untested_warning() {
+                warn!("{}", warning);
+            }
+        }
+
+        if let (true, Some(network), Some(ac_id)) = (discover_ac_id, &network, &srun.ac_id) {
+            if let Err(e) = cache::save_ac_id(network, ac_id) {
+                warn!("failed to cache ac_id: {}", e);
+            }
+            discover_ac_id = false;
+        }
 
         info!(
             "starting daemon ({}) with polling interval={}s",
             self.username, poll_interval,
         );
+        if let Some(ip6) = srun.ip6 {
+            info!("dual-stack enabled, keeping {} alive as well", ip6);
+        }
 
         loop {
             let tick = srun_ticker.tick();
@@ -88,10 +154,33 @@ impl SrunDaemon {
                         Ok(resp) => {
                             match resp.error.as_str() {
                                 "ok" => {
-                                    info!("{} ({}): login success, {}", resp.client_ip, self.username, resp.suc_msg.unwrap_or_default());
+                                    // remember the ac_id detected again after a stale cached one
+                                    if let (true, Some(network), Some(ac_id)) = (discover_ac_id, &network, &srun.ac_id) {
+                                        if let Err(e) = cache::save_ac_id(network, ac_id) {
+                                            warn!("failed to cache ac_id: {}", e);
+                                        }
+                                        discover_ac_id = false;
+                                    }
+                                    match srun.complete_double_auth(false).await {
+                                        Ok(_) => info!("{} ({}): login success, {}", resp.client_ip, self.username, resp.suc_msg.unwrap_or_default()),
+                                        Err(e) => warn!("{} ({}): {}", resp.client_ip, self.username, e),
+                                    }
                                 }
                                 _ => {
                                     warn!("{} ({}): login failed, {}", resp.client_ip, self.username, resp.error);
+
+                                    // the cached ac_id may be stale, forget it and detect it again on the next login
+                                    if from_cache {
+                                        if let Some(network) = &network {
+                                            if let Err(e) = cache::forget_ac_id(network) {
+                                                warn!("failed to forget cached ac_id: {}", e);
+                                            }
+                                        }
+                                        info!("{}: detecting ac_id again on the next login", self.username);
+                                        srun.ac_id = None;
+                                        from_cache = false;
+                                        discover_ac_id = true;
+                                    }
                                 }
                             }
                         }
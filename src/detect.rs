@@ -0,0 +1,335 @@
+use crate::client::CAPTIVE_PORTAL_TEST;
+use crate::transport::HttpResponse;
+use crate::transport::HttpTransport;
+
+use std::fmt;
+
+use anyhow::bail;
+use anyhow::Result;
+use owo_colors::OwoColorize;
+use owo_colors::Stream::Stdout;
+use url::Url;
+
+/// A `generate_204` style URL, which returns 204 if there is no captive portal
+pub const GENERATE_204: &str = "http://connect.rom.miui.com/generate_204";
+
+/// A captive portal detection strategy to discover the ac_id of the current device
+#[derive(Debug, Clone)]
+pub enum Strategy {
+    /// Probe a `generate_204` style URL, which is redirected to the portal if not logged in
+    Generate204(String),
+    /// Visit a URL without following redirects, and read the `Location` header
+    LocationHeader(String),
+    /// Visit a URL, and parse HTML meta refresh or JS `location.href` redirects in the page
+    PageRedirect(String),
+    /// Visit the portal directly
+    Portal(String),
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Generate204(url) => write!(f, "generate_204 probe `{}`", url),
+            Strategy::LocationHeader(url) => write!(f, "location header of `{}`", url),
+            Strategy::PageRedirect(url) => write!(f, "page redirect of `{}`", url),
+            Strategy::Portal(url) => write!(f, "portal `{}`", url),
+        }
+    }
+}
+
+/// Default detection strategies, tried in order
+///
+/// Visiting the portal directly is the last resort, because of ITC's double authentication
+/// mechanism. https://itc.bit.edu.cn/fwzn/zxbl/f2c0c8e939ce4e9cace880d5403fe4b5.htm
+pub fn default_strategies(portal: &str) -> Vec<Strategy> {
+    vec![
+        Strategy::LocationHeader(CAPTIVE_PORTAL_TEST.into()),
+        Strategy::Generate204(GENERATE_204.into()),
+        Strategy::PageRedirect(CAPTIVE_PORTAL_TEST.into()),
+        Strategy::Portal(portal.into()),
+    ]
+}
+
+/// What a detection strategy found, either the ac_id or why it found nothing
+#[derive(Debug)]
+pub struct Finding {
+    pub strategy: Strategy,
+    pub result: Result<String, String>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.result {
+            Ok(ac_id) => write!(f, "{}: found ac_id={}", self.strategy, ac_id),
+            Err(reason) => write!(f, "{}: {}", self.strategy, reason),
+        }
+    }
+}
+
+/// Get the ac_id from the query (`?ac_id=1`) or path (`/index_1.html`) of a portal URL
+pub fn ac_id_from_url(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    if let Some((_, ac_id)) = url.query_pairs().find(|(key, _)| key == "ac_id") {
+        return Some(ac_id.into_owned());
+    }
+    let page = url.path_segments()?.next_back()?;
+    let ac_id = page.strip_prefix("index_")?.strip_suffix(".html")?;
+    match !ac_id.is_empty() && ac_id.chars().all(|c| c.is_ascii_digit()) {
+        true => Some(ac_id.into()),
+        false => None,
+    }
+}
+
+/// Find the target of an HTML meta refresh or JS `location` redirect in a page
+pub fn find_page_redirect(body: &str) -> Option<String> {
+    let lower = body.to_ascii_lowercase();
+
+    // <meta http-equiv="refresh" content="0;url=http://10.0.0.55/...">
+    if let Some(meta) = lower
+        .find("http-equiv=\"refresh\"")
+        .or(lower.find("http-equiv='refresh'"))
+    {
+        let tag_start = lower[..meta].rfind('<').unwrap_or(meta);
+        let tag_end = lower[meta..].find('>').map_or(lower.len(), |i| meta + i);
+        if let Some(i) = lower[tag_start..tag_end].find("url=") {
+            let target = &body[tag_start + i + 4..tag_end];
+            let target = target.split(['"', '\'']).next().unwrap_or_default().trim();
+            if !target.is_empty() {
+                return Some(target.into());
+            }
+        }
+    }
+
+    // location.href = "...", location.replace("..."), window.location = '...'
+    for pattern in [
+        "location.href",
+        "location.replace",
+        "location.assign",
+        "location",
+    ] {
+        let mut rest = lower.as_str();
+        let mut offset = 0;
+        while let Some(i) = rest.find(pattern) {
+            let start = offset + i + pattern.len();
+            let after = body[start..].trim_start();
+            if let Some(quoted) = after.strip_prefix(['=', '(']) {
+                let quoted = quoted.trim_start();
+                if let Some(quote) = quoted.chars().next().filter(|c| matches!(c, '"' | '\'')) {
+                    if let Some(end) = quoted[1..].find(quote) {
+                        return Some(quoted[1..1 + end].into());
+                    }
+                }
+            }
+            offset = start;
+            rest = &lower[offset..];
+        }
+    }
+    None
+}
+
+/// Resolve a possibly relative redirect target against the URL of the page
+pub(crate) fn resolve(base: &str, target: &str) -> String {
+    Url::parse(base)
+        .and_then(|base| base.join(target))
+        .map(|url| url.to_string())
+        .unwrap_or_else(|_| target.into())
+}
+
+/// Get the ac_id from the final URL of a response, or from a redirect in its page
+fn ac_id_from_response(resp: &HttpResponse) -> Result<String, String> {
+    if let Some(ac_id) = ac_id_from_url(&resp.url) {
+        return Ok(ac_id);
+    }
+    match find_page_redirect(&resp.body) {
+        Some(target) => {
+            let target = resolve(&resp.url, &target);
+            ac_id_from_url(&target)
+                .ok_or_else(|| format!("page redirects to `{}` without ac_id", target))
+        }
+        None => Err(format!(
+            "no ac_id in `{}` ({}) or redirects in its page",
+            resp.url, resp.status
+        )),
+    }
+}
+
+impl Strategy {
+    /// Run the strategy, returning the ac_id or why nothing was found
+    pub async fn run<T: HttpTransport>(&self, client: &T) -> Result<String, String> {
+        match self {
+            Strategy::Generate204(url) => {
+                let resp = client.get(url, &[]).await.map_err(request_failed)?;
+                if resp.status == 204 {
+                    return Err("got 204, no captive portal (already logged in?)".into());
+                }
+                ac_id_from_response(&resp)
+            }
+            Strategy::LocationHeader(url) => {
+                let resp = client
+                    .get_no_redirect(url, &[])
+                    .await
+                    .map_err(request_failed)?;
+                if !(300..400).contains(&resp.status) {
+                    return Err(format!("not redirected ({})", resp.status));
+                }
+                let location = resp
+                    .header("location")
+                    .ok_or_else(|| format!("redirected ({}) without location", resp.status))?;
+                let location = resolve(&resp.url, location);
+                ac_id_from_url(&location)
+                    .ok_or_else(|| format!("redirected to `{}` without ac_id", location))
+            }
+            Strategy::PageRedirect(url) | Strategy::Portal(url) => {
+                let resp = client.get(url, &[]).await.map_err(request_failed)?;
+                ac_id_from_response(&resp)
+            }
+        }
+    }
+}
+
+fn request_failed(err: anyhow::Error) -> String {
+    format!("request failed, {:#}", err)
+}
+
+/// Detect the ac_id of the current device, trying each strategy in order until one succeeds
+///
+/// If all of them fail, the error explains what each strategy found.
+pub async fn detect_ac_id<T: HttpTransport>(
+    client: &T,
+    strategies: &[Strategy],
+    verbose: bool,
+) -> Result<String> {
+    let mut findings = Vec::new();
+    for strategy in strategies {
+        let finding = Finding {
+            strategy: strategy.clone(),
+            result: strategy.run(client).await,
+        };
+        if verbose {
+            println!(
+                "{} detection {}",
+                "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                finding.if_supports_color(Stdout, |t| t.dimmed())
+            );
+        }
+        if let Ok(ac_id) = finding.result {
+            return Ok(ac_id);
+        }
+        findings.push(finding);
+    }
+
+    let findings: Vec<String> = findings.iter().map(|f| format!("  - {}", f)).collect();
+    bail!(
+        "failed to detect ac_id, specify it manually with `{}`\n{}",
+        "--ac-id".if_supports_color(Stdout, |t| t.underline()),
+        findings.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ScriptedTransport;
+
+    #[test]
+    fn ac_id_from_urls() {
+        for (url, ac_id) in [
+            (
+                "http://10.0.0.55/srun_portal_pc?ac_id=8&theme=bit",
+                Some("8"),
+            ),
+            (
+                "https://10.0.0.55/srun_portal_pc?theme=bit&ac_id=1",
+                Some("1"),
+            ),
+            ("http://10.0.0.55/index_23.html", Some("23")),
+            ("https://portal.example.edu/index_5.html?foo=bar", Some("5")),
+            // the query takes precedence over the path
+            ("http://10.0.0.55/index_1.html?ac_id=8", Some("8")),
+            ("http://10.0.0.55/srun_portal_pc?theme=bit", None),
+            ("http://10.0.0.55/index_.html", None),
+            ("http://10.0.0.55/index_1a.html", None),
+            ("http://10.0.0.55/", None),
+            ("10.0.0.55/index_1.html", None),
+            ("http://[::1/index_1.html", None),
+            ("", None),
+        ] {
+            assert_eq!(ac_id_from_url(url).as_deref(), ac_id, "{}", url);
+        }
+    }
+
+    #[test]
+    fn page_redirects() {
+        for (body, target) in [
+            (
+                r#"<meta http-equiv="refresh" content="0;url=http://10.0.0.55/index_1.html">"#,
+                Some("http://10.0.0.55/index_1.html"),
+            ),
+            (
+                "<META HTTP-EQUIV='Refresh' CONTENT='0; URL=https://10.0.0.55/srun_portal_pc?ac_id=8'>",
+                Some("https://10.0.0.55/srun_portal_pc?ac_id=8"),
+            ),
+            (
+                r#"<script>location.href="http://10.0.0.55/srun_portal_pc?ac_id=8&theme=bit"</script>"#,
+                Some("http://10.0.0.55/srun_portal_pc?ac_id=8&theme=bit"),
+            ),
+            (
+                "<script>window.location.href = 'https://10.0.0.55/index_1.html';</script>",
+                Some("https://10.0.0.55/index_1.html"),
+            ),
+            (
+                r#"<script>location.replace("/srun_portal_pc?ac_id=1")</script>"#,
+                Some("/srun_portal_pc?ac_id=1"),
+            ),
+            (
+                "<script>window.location = '/index_1.html'</script>",
+                Some("/index_1.html"),
+            ),
+            // reading the location is not a redirect
+            ("<script>var url = location.href + '?a=1';</script>", None),
+            (r#"<meta http-equiv="refresh" content="30">"#, None),
+            ("<html><body>Success</body></html>", None),
+        ] {
+            assert_eq!(find_page_redirect(body).as_deref(), target, "{}", body);
+        }
+    }
+
+    #[test]
+    fn resolves_relative_redirects() {
+        assert_eq!(
+            resolve("http://10.0.0.55/", "/index_1.html"),
+            "http://10.0.0.55/index_1.html"
+        );
+        assert_eq!(
+            resolve("http://10.0.0.55/", "https://10.0.0.55/index_1.html"),
+            "https://10.0.0.55/index_1.html"
+        );
+        assert_eq!(resolve("not a url", "/index_1.html"), "/index_1.html");
+    }
+
+    #[tokio::test]
+    async fn detects_ac_id_from_page_redirect() {
+        let transport = ScriptedTransport::new().respond(
+            "http://www.bit.edu.cn/",
+            "<script>top.self.location.href='http://10.0.0.55/srun_portal_pc?ac_id=8&theme=bit'</script>",
+        );
+        let strategies = [Strategy::PageRedirect("http://www.bit.edu.cn".into())];
+        let ac_id = detect_ac_id(&transport, &strategies, false).await.unwrap();
+        assert_eq!(ac_id, "8");
+    }
+
+    #[tokio::test]
+    async fn explains_failed_detection() {
+        let transport = ScriptedTransport::new().respond(
+            "http://www.bit.edu.cn/",
+            "<script>location.href='http://10.0.0.55/srun_portal_pc?theme=bit'</script>",
+        );
+        let strategies = [Strategy::PageRedirect("http://www.bit.edu.cn".into())];
+        let err = detect_ac_id(&transport, &strategies, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains(
+            "page redirects to `http://10.0.0.55/srun_portal_pc?theme=bit` without ac_id"
+        ));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::client::get_login_state;
+use crate::client::SrunClient;
+use crate::client::SrunLoginState;
+use crate::client::CAPTIVE_PORTAL_TEST;
+use crate::detect::default_strategies;
+use crate::detect::detect_ac_id;
+use crate::detect::GENERATE_204;
+use crate::double_auth::check_connectivity;
+use crate::double_auth::Connectivity;
+use crate::net::get_local_ip;
+use crate::transport::HttpTransport;
+use crate::user::parse_bit_user_config;
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use tokio::net::lookup_host;
+use tokio::net::TcpSocket;
+use tokio::time::timeout;
+use url::Url;
+
+/// Timeout of the DNS and TCP checks
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of a single diagnostic check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+/// A single diagnostic check, with a remediation hint if it did not pass
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Skip,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn with(
+        name: &'static str,
+        status: Status,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// `host:port` of a URL, with the scheme's default port if not specified
+fn host_port(url: &str) -> Result<(String, u16)> {
+    let url = Url::parse(url)?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("`{}` has no host", url))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("`{}` has no port", url))?;
+    Ok((
+        host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        port,
+    ))
+}
+
+async fn resolve_host(url: &str) -> Result<SocketAddr> {
+    let (host, port) = host_port(url)?;
+    let mut addrs = timeout(CHECK_TIMEOUT, lookup_host((host.as_str(), port)))
+        .await
+        .map_err(|_| anyhow!("timed out after {}s", CHECK_TIMEOUT.as_secs()))??;
+    addrs
+        .next()
+        .ok_or_else(|| anyhow!("no addresses found for `{}`", host))
+}
+
+async fn connect(addr: SocketAddr, source_ip: Option<IpAddr>) -> Result<()> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(ip) = source_ip {
+        socket.bind(SocketAddr::new(ip, 0))?;
+    }
+    timeout(CHECK_TIMEOUT, socket.connect(addr))
+        .await
+        .map_err(|_| anyhow!("timed out after {}s", CHECK_TIMEOUT.as_secs()))??;
+    Ok(())
+}
+
+/// Run all diagnostic checks in order, each one is reported even if an earlier one failed
+///
+/// `client` is only used for its portal, username (if not found in the config file) and transport,
+/// the device is never logged in or out.
+pub async fn diagnose<T: HttpTransport>(
+    mut client: SrunClient<T>,
+    config_path: &Option<String>,
+    source_ip: Option<IpAddr>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let portal = client.portal.clone();
+
+    // config file
+    checks.push(match parse_bit_user_config(config_path) {
+        Ok(user) => {
+            if client.username.is_empty() {
+                client.username = user.username.unwrap_or_default();
+            }
+            Check::pass("config file", "found and parsed")
+        }
+        Err(e) => Check::with(
+            "config file",
+            Status::Warn,
+            format!("{:#}", e),
+            "list searched paths with `bitsrun config-paths`, or pass credentials with `-u` and `-p`",
+        ),
+    });
+
+    // dns, needed to discover ac_id through captive portal redirects
+    checks.push(match resolve_host(CAPTIVE_PORTAL_TEST).await {
+        Ok(addr) => Check::pass(
+            "dns",
+            format!("{} resolved to {}", CAPTIVE_PORTAL_TEST, addr.ip()),
+        ),
+        Err(e) => Check::with(
+            "dns",
+            Status::Fail,
+            format!("failed to resolve {}, {:#}", CAPTIVE_PORTAL_TEST, e),
+            "check your DNS settings, or set `ac_id` in the config file to skip discovery",
+        ),
+    });
+
+    // tcp reachability of the portal
+    let portal_addr = resolve_host(&portal).await;
+    checks.push(match &portal_addr {
+        Ok(addr) => match connect(*addr, source_ip).await {
+            Ok(_) => Check::pass("portal reachable", format!("connected to {}", addr)),
+            Err(e) => Check::with(
+                "portal reachable",
+                Status::Fail,
+                format!("failed to connect to {}, {:#}", addr, e),
+                "make sure you are on the campus network, or pick the right one with `--interface`",
+            ),
+        },
+        Err(e) => Check::with(
+            "portal reachable",
+            Status::Fail,
+            format!("invalid portal `{}`, {:#}", portal, e),
+            "the portal should be a URL like `http://10.0.0.55`",
+        ),
+    });
+
+    // rad_user_info
+    let login_state = get_login_state(&client.http_client, &portal, None, false).await;
+    checks.push(match &login_state {
+        Ok(state) if state.error == "ok" => Check::pass(
+            "rad_user_info",
+            format!(
+                "online as {}",
+                state.user_name.clone().unwrap_or_default()
+            ),
+        ),
+        Ok(state) => Check::pass("rad_user_info", format!("offline ({})", state.error)),
+        Err(e) => Check::with(
+            "rad_user_info",
+            Status::Fail,
+            format!("{:#}", e),
+            "if the portal is reachable, its response format may have changed, please report it with `--verbose` output",
+        ),
+    });
+    let login_state = login_state.ok();
+    let online = matches!(&login_state, Some(SrunLoginState { error, .. }) if error == "ok");
+
+    // ac_id discovery, there is no captive portal to redirect to once online
+    checks.push(
+        match detect_ac_id(&client.http_client, &default_strategies(&portal), false).await {
+            Ok(ac_id) => Check::pass("ac_id discovery", format!("ac_id is {}", ac_id)),
+            Err(_) if online => Check::skip(
+                "ac_id discovery",
+                "already online, no captive portal to discover ac_id from",
+            ),
+            Err(e) => Check::with(
+                "ac_id discovery",
+                Status::Fail,
+                format!("{:#}", e),
+                "set `ac_id` in the config file or specify it with `--ac-id`",
+            ),
+        },
+    );
+
+    // get_challenge round-trip
+    checks.push(match &login_state {
+        Some(state) => match client.get_challenge(state.online_ip, false).await {
+            Ok(_) => Check::pass("get_challenge", "received challenge"),
+            Err(e) => Check::with(
+                "get_challenge",
+                Status::Fail,
+                format!("{:#}", e),
+                "the portal may be overloaded or blocking this device, try again later",
+            ),
+        },
+        None => Check::skip("get_challenge", "no online_ip from rad_user_info"),
+    });
+
+    // local address vs. the address the portal sees
+    let local_ip = source_ip.or_else(|| {
+        portal_addr
+            .as_ref()
+            .ok()
+            .and_then(|addr| get_local_ip(&addr.to_string()))
+    });
+    checks.push(match (&login_state, local_ip) {
+        (Some(state), Some(local_ip)) if state.online_ip == local_ip => {
+            Check::pass("nat", format!("portal sees local address {}", local_ip))
+        }
+        (Some(state), Some(local_ip)) => Check::with(
+            "nat",
+            Status::Warn,
+            format!(
+                "local address {}, but the portal sees {}",
+                local_ip, state.online_ip
+            ),
+            "this device is behind NAT (e.g., a router), log the router in instead, or use `--ip`",
+        ),
+        _ => Check::skip("nat", "local or online address unknown"),
+    });
+
+    // external connectivity
+    checks.push(match online {
+        true => match check_connectivity(&client.http_client).await {
+            Connectivity::Online => {
+                Check::pass("connectivity", format!("{} returned 204", GENERATE_204))
+            }
+            Connectivity::Intercepted(url) => Check::with(
+                "connectivity",
+                Status::Fail,
+                format!("traffic is intercepted by `{}`", url),
+                "complete the second web authentication of this network, or try `bitsrun login --force`",
+            ),
+            Connectivity::Unknown(reason) => Check::with(
+                "connectivity",
+                Status::Fail,
+                reason,
+                "online but unable to reach the internet, try `bitsrun login --force`",
+            ),
+        },
+        false => Check::skip("connectivity", "not logged in"),
+    });
+
+    checks
+}
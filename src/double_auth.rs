@@ -0,0 +1,65 @@
+use crate::detect::find_page_redirect;
+use crate::detect::resolve;
+use crate::detect::GENERATE_204;
+use crate::transport::HttpTransport;
+
+use std::fmt;
+
+use url::Url;
+
+/// Whether traffic gets through after logging in to the portal
+///
+/// On some networks (e.g., ITC's double authentication), a successful portal login is followed by
+/// a second web authentication, and traffic is still intercepted until it is completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Connectivity {
+    /// `generate_204` returned 204
+    Online,
+    /// Traffic is redirected to a web authentication at this URL
+    Intercepted(String),
+    /// `generate_204` failed or returned something unexpected, with the reason
+    Unknown(String),
+}
+
+impl fmt::Display for Connectivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Connectivity::Online => write!(f, "online"),
+            Connectivity::Intercepted(url) => write!(f, "intercepted by `{}`", url),
+            Connectivity::Unknown(reason) => write!(f, "unknown, {}", reason),
+        }
+    }
+}
+
+/// Probe `generate_204` without following redirects, to see if traffic is intercepted
+pub async fn check_connectivity<T: HttpTransport>(client: &T) -> Connectivity {
+    let resp = match client.get_no_redirect(GENERATE_204, &[]).await {
+        Ok(resp) => resp,
+        Err(e) => return Connectivity::Unknown(format!("request failed, {:#}", e)),
+    };
+    if resp.status == 204 {
+        return Connectivity::Online;
+    }
+    let target = match (300..400).contains(&resp.status) {
+        true => resp.header("location").map(str::to_string),
+        false => find_page_redirect(&resp.body),
+    };
+    match target {
+        Some(target) => Connectivity::Intercepted(resolve(&resp.url, &target)),
+        None => Connectivity::Unknown(format!(
+            "{} returned {} instead of 204",
+            GENERATE_204, resp.status
+        )),
+    }
+}
+
+/// Whether an intercepting URL is a second login on the SRUN portal itself, which can be completed
+/// by logging in again with the ac_id it carries
+pub fn is_srun_stage(portal: &str, url: &str) -> bool {
+    let host = |url: &str| {
+        Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+    };
+    host(portal).is_some() && host(portal) == host(url)
+}
@@ -0,0 +1,20 @@
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod cache;
+pub mod client;
+pub mod config;
+pub mod daemon;
+pub mod detect;
+pub mod doctor;
+pub mod double_auth;
+pub mod net;
+pub mod probe;
+pub mod router;
+pub mod scheme;
+pub mod secret;
+pub mod tables;
+pub mod trace;
+pub mod transport;
+pub mod user;
+pub mod verify;
+pub mod xencode;
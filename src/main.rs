@@ -1,39 +1,136 @@
 mod cli;
-mod client;
-mod config;
-mod daemon;
-mod tables;
-mod user;
-mod xencode;
 
+use std::fmt;
+use std::io;
+use std::io::IsTerminal;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
 use cli::ClientArgs;
+use cli::DoctorArgs;
 use cli::StatusArgs;
 use enable_ansi_support::enable_ansi_support;
 use owo_colors::OwoColorize;
 use owo_colors::Stream::Stderr;
 use owo_colors::Stream::Stdout;
+use percent_encoding::percent_decode_str;
 
+use bitsrun::cache;
+use bitsrun::client::get_login_state;
+use bitsrun::client::SrunClient;
+use bitsrun::client::SRUN_PORTAL;
+use bitsrun::config;
+use bitsrun::daemon::SrunDaemon;
+use bitsrun::doctor;
+use bitsrun::doctor::Status;
+use bitsrun::net;
+use bitsrun::probe::Capabilities;
+use bitsrun::router::SrunRouter;
+use bitsrun::tables::print_config_paths;
+use bitsrun::tables::print_login_state;
+use bitsrun::trace::ReplayTransport;
+use bitsrun::trace::TracingTransport;
+use bitsrun::transport::HttpResponse;
+use bitsrun::transport::HttpTransport;
+use bitsrun::transport::ReqwestTransport;
+use bitsrun::user;
+use bitsrun::user::MissingCredentials;
+use bitsrun::verify::verify_online;
+use bitsrun::verify::Probe;
+use bitsrun::verify::Verification;
+use bitsrun::xencode;
 use cli::Arguments;
 use cli::Commands;
-use client::get_login_state;
-use client::SrunClient;
-use daemon::SrunDaemon;
-use tables::print_config_paths;
-use tables::print_login_state;
+use cli::RouterCommands;
+
+/// Exit code when credentials or confirmations are missing and can't be prompted for (`EX_CONFIG`
+/// of sysexits.h)
+const EXIT_MISSING_CREDENTIALS: i32 = 78;
+
+/// Logging out another device needs confirmation, which can't be prompted for
+#[derive(Debug)]
+struct MissingConfirmation(IpAddr);
+
+impl fmt::Display for MissingConfirmation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is not this device, and prompting for confirmation is disabled (`--no-input` or no \
+            TTY), pass `--yes` to log it out",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for MissingConfirmation {}
+
+/// Transport of portal requests, recorded to a file with `--trace-file` or served from one with
+/// `--replay`
+enum CliTransport {
+    Direct(ReqwestTransport),
+    Traced(TracingTransport<ReqwestTransport>),
+    Replay(ReplayTransport),
+}
+
+impl CliTransport {
+    fn new(args: &Arguments, source_ip: Option<IpAddr>) -> Result<Self> {
+        if let Some(path) = &args.replay {
+            return Ok(Self::Replay(ReplayTransport::load(path)?));
+        }
+        let http_client = net::build_http_client(source_ip)?;
+        Ok(match &args.trace_file {
+            Some(path) => Self::Traced(TracingTransport::new(http_client, path, args.anonymize)),
+            None => Self::Direct(http_client),
+        })
+    }
+}
+
+impl HttpTransport for CliTransport {
+    async fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        match self {
+            Self::Direct(http_client) => http_client.get(url, query).await,
+            Self::Traced(http_client) => http_client.get(url, query).await,
+            Self::Replay(http_client) => http_client.get(url, query).await,
+        }
+    }
+
+    async fn get_no_redirect(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        match self {
+            Self::Direct(http_client) => http_client.get_no_redirect(url, query).await,
+            Self::Traced(http_client) => http_client.get_no_redirect(url, query).await,
+            Self::Replay(http_client) => http_client.get_no_redirect(url, query).await,
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     if let Err(err) = cli().await {
+        // missing input is reported as is, with its own exit code for scripts
+        let missing = err
+            .downcast_ref::<MissingCredentials>()
+            .map(ToString::to_string)
+            .or_else(|| {
+                err.downcast_ref::<MissingConfirmation>()
+                    .map(ToString::to_string)
+            });
         eprintln!(
             "{} {}: {}",
             "bitsrun".if_supports_color(Stderr, |t| t.bright_red()),
             "(error)".if_supports_color(Stderr, |t| t.dimmed()),
-            err
+            match &missing {
+                Some(missing) => missing.to_owned(),
+                None => err.to_string(),
+            }
         );
-        std::process::exit(1);
+        std::process::exit(match missing {
+            Some(_) => EXIT_MISSING_CREDENTIALS,
+            None => 1,
+        });
     }
 }
 
@@ -44,43 +141,97 @@ async fn cli() -> Result<()> {
     }
 
     let args = Arguments::parse();
+    config::allow_insecure(args.insecure);
 
-    // reusable http client
-    let http_client = reqwest::Client::new();
+    // local address to bind portal requests to, from `--source-ip` or `--interface`
+    let source_ip = net::resolve_source_ip(&args.interface, &args.source_ip)?;
 
     // commands
     match &args.command {
         // check login status
         Some(Commands::Status(status_args)) => {
+            let http_client = CliTransport::new(&args, source_ip)?;
             srun_status(http_client, status_args, args.verbose).await?
         }
 
         // login or logout
         Some(Commands::Login(client_args)) | Some(Commands::Logout(client_args)) => {
+            let interactive = !args.no_input && io::stdin().is_terminal();
             let bit_user = user::finalize_bit_user(
                 &client_args.username,
                 &client_args.password,
                 client_args.dm,
+                client_args.double_stack,
                 &client_args.config,
                 matches!(args.command, Some(Commands::Login(_))),
+                interactive,
             )
             .with_context(|| "unable to parse user credentials")?;
 
-            let srun_client = SrunClient::new(
-                bit_user.username,
-                bit_user.password,
-                Some(http_client),
-                client_args.ip,
-                Some(bit_user.dm),
-            )
-            .await?;
+            // command line arguments take priority over the config file
+            let source_ip = match source_ip {
+                Some(ip) => Some(ip),
+                None => net::resolve_source_ip(&bit_user.interface, &bit_user.source_ip)?,
+            };
+
+            // ac_id priority: command line > config file > cache of the current network > discovery
+            // replayed sessions are reproduced as recorded, without the ac_id cache of this network
+            let network = match args.replay {
+                Some(_) => None,
+                None => cache::network_key(SRUN_PORTAL, source_ip),
+            };
+            let cached_ac_id = match (&network, client_args.refresh_ac_id) {
+                (Some(network), false) => cache::load_ac_id(network),
+                _ => None,
+            };
+            let ac_id = client_args.ac_id.clone().or(bit_user.ac_id);
+            let from_cache = ac_id.is_none() && cached_ac_id.is_some();
+            let ac_id = ac_id.or(cached_ac_id);
+            let discover_ac_id = ac_id.is_none();
+
+            // the ip sent to the portal defaults to the address requests are bound to, an IPv6 one
+            // is only sent on dual-stack logins, as it implies them otherwise
+            let bound_ip6 = source_ip.filter(|ip| ip.is_ipv6() && bit_user.double_stack);
+            let mut builder = SrunClient::builder()
+                .username(bit_user.username)
+                .password(bit_user.password)
+                .ip(client_args.ip.or(source_ip.filter(|ip| ip.is_ipv4())))
+                .ip6(client_args.ip6.or(bound_ip6))
+                .ac_id(ac_id)
+                .dm(bit_user.dm)
+                .double_stack(bit_user.double_stack)
+                .device(
+                    client_args.os.clone().or(bit_user.os),
+                    client_args.device_name.clone().or(bit_user.name),
+                );
+            if let Some(scheme) = bit_user.scheme {
+                builder = builder.scheme(scheme);
+            }
+            let mut srun_client = builder.build_with(CliTransport::new(&args, source_ip)?);
+            srun_client.discover(args.verbose).await?;
+
+            // remember the discovered ac_id for the next time on this network
+            if let (true, Some(network), Some(ac_id)) =
+                (discover_ac_id, &network, &srun_client.ac_id)
+            {
+                remember_ac_id(network, ac_id);
+            }
+
+            // a cached ac_id is detected again if logging in with it fails
+            let cached_network = network.as_deref().filter(|_| from_cache);
 
             match &args.command {
+                Some(Commands::Login(_)) if client_args.dry_run => {
+                    srun_dry_run(&mut srun_client, client_args, args.verbose).await?
+                }
                 Some(Commands::Login(_)) => {
-                    srun_login(&srun_client, client_args, args.verbose).await?
+                    srun_login(&mut srun_client, client_args, cached_network, args.verbose).await?
+                }
+                Some(Commands::Logout(_)) if client_args.dry_run => {
+                    bail!("`--dry-run` is only supported when logging in")
                 }
                 Some(Commands::Logout(_)) => {
-                    srun_logout(&srun_client, client_args, args.verbose).await?
+                    srun_logout(&mut srun_client, client_args, interactive, args.verbose).await?
                 }
                 _ => {}
             };
@@ -89,7 +240,44 @@ async fn cli() -> Result<()> {
         Some(Commands::KeepAlive(daemon_args)) => {
             let config_path = daemon_args.config.to_owned();
             let daemon = SrunDaemon::new(config_path)?;
-            daemon.start(http_client).await?;
+            daemon.start(source_ip, daemon_args.refresh_ac_id).await?;
+        }
+
+        Some(Commands::Router(router_args)) => {
+            let router = SrunRouter::new(router_args.config.to_owned())?;
+            let http_client = CliTransport::new(&args, router.source_ip(source_ip)?)?;
+            match router_args.command {
+                RouterCommands::Login => {
+                    router
+                        .login(http_client, router_args.force, args.verbose)
+                        .await?
+                }
+                RouterCommands::Logout => {
+                    router
+                        .logout(http_client, router_args.force, args.verbose)
+                        .await?
+                }
+                RouterCommands::Status => router.status(http_client, args.verbose).await?,
+                RouterCommands::KeepAlive => router.start(http_client).await?,
+            }
+        }
+
+        Some(Commands::Doctor(doctor_args)) => {
+            let http_client = CliTransport::new(&args, source_ip)?;
+            srun_doctor(http_client, doctor_args, source_ip).await?
+        }
+
+        Some(Commands::DecodeInfo(decode_args)) => {
+            // `info` is percent-encoded when copied from a URL (in either case, e.g., `%7B` or
+            // `%7b`), which is a no-op otherwise as `%` is not in its alphabet, unlike `+`
+            let info = percent_decode_str(&decode_args.info)
+                .decode_utf8()
+                .with_context(|| "info is not valid percent-encoded utf-8")?;
+            let decoded = xencode::decode_info(&info, &decode_args.token)?;
+            match serde_json::from_str::<serde_json::Value>(&decoded) {
+                Ok(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+                Err(_) => println!("{}", decoded),
+            }
         }
 
         Some(Commands::ConfigPaths) => print_config_paths(),
@@ -101,12 +289,12 @@ async fn cli() -> Result<()> {
 }
 
 async fn srun_status(
-    http_client: reqwest::Client,
+    http_client: CliTransport,
     status_args: &StatusArgs,
     verbose: bool,
 ) -> Result<()> {
     // only verbose on args.verbose = true and not outputting json
-    let login_state = get_login_state(&http_client, verbose).await?;
+    let login_state = get_login_state(&http_client, SRUN_PORTAL, None, verbose).await?;
 
     // output json
     if status_args.json & !verbose {
@@ -116,15 +304,16 @@ async fn srun_status(
     }
 
     // output human readable
+    let online_ips = match login_state.online_ipv6() {
+        Some(ip6) => format!("{}, {}", login_state.online_ip, ip6),
+        None => login_state.online_ip.to_string(),
+    };
     match login_state.error.as_str() {
         "ok" => {
             println!(
                 "{} {} {} is online",
                 "bitsrun:".if_supports_color(Stdout, |t| t.bright_green()),
-                &login_state
-                    .online_ip
-                    .to_string()
-                    .if_supports_color(Stdout, |t| t.underline()),
+                online_ips.if_supports_color(Stdout, |t| t.underline()),
                 format!("({})", login_state.user_name.clone().unwrap_or_default())
                     .if_supports_color(Stdout, |t| t.dimmed())
             );
@@ -136,47 +325,226 @@ async fn srun_status(
             println!(
                 "{} {} is offline",
                 "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
-                login_state
-                    .online_ip
-                    .to_string()
-                    .if_supports_color(Stdout, |t| t.underline())
+                online_ips.if_supports_color(Stdout, |t| t.underline())
             );
         }
     };
     Ok(())
 }
 
+/// Warn if the probed portal differs from the tested release
+fn warn_untested(caps: Option<&Capabilities>) {
+    if let Some(warning) = caps.and_then(Capabilities::untested_warning) {
+        println!(
+            "{} {}",
+            "warning:".if_supports_color(Stdout, |t| t.yellow()),
+            warning
+        );
+    }
+}
+
+/// Cache the discovered ac_id of a network, only warning on failure
+fn remember_ac_id(network: &str, ac_id: &str) {
+    if let Err(e) = cache::save_ac_id(network, ac_id) {
+        println!(
+            "{} {}",
+            "warning:".if_supports_color(Stdout, |t| t.yellow()),
+            e
+        );
+    }
+}
+
+/// Login, where `cached_network` is the network the ac_id was cached for, if it was
 async fn srun_login(
-    srun_client: &SrunClient,
+    srun_client: &mut SrunClient<CliTransport>,
     client_args: &ClientArgs,
+    cached_network: Option<&str>,
     verbose: bool,
 ) -> Result<()> {
-    let resp = srun_client.login(client_args.force, verbose).await?;
-    match resp.error.as_str() {
-        "ok" => println!(
-            "{} {} {} logged in",
+    // the scheme is probed on login if not specified, an untested one may be why login fails
+    let resp = srun_client.login(client_args.force, verbose).await;
+    warn_untested(srun_client.capabilities.as_ref());
+    let mut resp = resp?;
+
+    // the cached ac_id may be stale (e.g., the network was rearranged), forget it and detect again
+    if let (false, Some(network)) = (resp.error == "ok", cached_network) {
+        if let Err(e) = cache::forget_ac_id(network) {
+            println!(
+                "{} {}",
+                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                e
+            );
+        }
+        let stale = srun_client.ac_id.take();
+        match srun_client.discover(verbose).await {
+            Ok(_) if srun_client.ac_id != stale => {
+                if verbose {
+                    println!(
+                        "{} login with cached ac_id={} failed, retrying with ac_id={}",
+                        "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                        stale.unwrap_or_default(),
+                        srun_client.ac_id.clone().unwrap_or_default()
+                    );
+                }
+                resp = srun_client.login(client_args.force, verbose).await?;
+                if let (true, Some(ac_id)) = (resp.error == "ok", &srun_client.ac_id) {
+                    remember_ac_id(network, ac_id);
+                }
+            }
+            _ => srun_client.ac_id = stale,
+        }
+    }
+    if resp.error != "ok" {
+        bail!(
+            "failed to login, {} {}",
+            resp.error,
+            format!("({})", resp.error_msg).if_supports_color(Stderr, |t| t.dimmed())
+        )
+    }
+    if verbose {
+        println!(
+            "{} first stage ok, checking that traffic gets through",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue())
+        );
+    }
+
+    // stage one succeeded, but the device may still be offline, only report it once it is not
+    srun_client.complete_double_auth(verbose).await?;
+    let verified = match client_args.verify {
+        true => {
+            let verification = Verification {
+                probe: match (&client_args.verify_url, &client_args.verify_dns) {
+                    (_, Some(host)) => Probe::Dns(host.clone()),
+                    (Some(url), None) => Probe::Url(url.clone()),
+                    (None, None) => Verification::default().probe,
+                },
+                timeout: Duration::from_secs(client_args.verify_timeout),
+                retries: client_args.verify_retries,
+            };
+            Some(verify_online(srun_client, &verification, verbose).await?)
+        }
+        false => None,
+    };
+
+    println!(
+        "{} {} {} logged in",
+        "bitsrun:".if_supports_color(Stdout, |t| t.bright_green()),
+        match resp
+            .online_ip6
+            .filter(|ip| !ip.is_unspecified())
+            .or(srun_client.ip6)
+        {
+            Some(ip6) => format!("{}, {}", resp.online_ip, ip6),
+            None => resp.online_ip.to_string(),
+        }
+        .if_supports_color(Stdout, |t| t.underline()),
+        format!("({})", resp.username.clone().unwrap_or_default())
+            .if_supports_color(Stdout, |t| t.dimmed())
+    );
+    if let Some(login_state) = verified {
+        println!(
+            "{} verified {} is online",
             "bitsrun:".if_supports_color(Stdout, |t| t.bright_green()),
-            resp.online_ip
+            login_state
+                .online_ip
                 .to_string()
-                .if_supports_color(Stdout, |t| t.underline()),
-            format!("({})", resp.username.clone().unwrap_or_default())
-                .if_supports_color(Stdout, |t| t.dimmed())
-        ),
-        _ => println!(
-            "{} failed to login, {} {}",
-            "bitsrun:".if_supports_color(Stdout, |t| t.red()),
-            resp.error,
-            format!("({})", resp.error_msg).if_supports_color(Stdout, |t| t.dimmed())
-        ),
+                .if_supports_color(Stdout, |t| t.underline())
+        );
+    }
+    Ok(())
+}
+
+async fn srun_dry_run(
+    srun_client: &mut SrunClient<CliTransport>,
+    client_args: &ClientArgs,
+    verbose: bool,
+) -> Result<()> {
+    if srun_client.dm && srun_client.password.is_empty() {
+        bail!("`--dry-run` requires a password for `dm` logins, which send no payload otherwise")
+    }
+
+    // pick the login scheme of the portal, unless computing a dry run offline
+    if srun_client.scheme.is_none() && client_args.challenge.is_none() {
+        let caps = srun_client.probe(verbose).await?;
+        warn_untested(Some(&caps));
+    }
+    let token = match &client_args.challenge {
+        Some(token) => token.clone(),
+        None => {
+            let ip = srun_client
+                .ip
+                .with_context(|| "ip of the current device is not discovered yet")?;
+            srun_client.get_challenge(ip, verbose).await?
+        }
     };
+    let payload = srun_client.login_payload(&token)?;
+
+    println!(
+        "{} dry run, computed login request (not sent)",
+        "bitsrun:".if_supports_color(Stdout, |t| t.blue())
+    );
+    let info_data = match client_args.show_password {
+        true => payload.info_data.expose().to_string(),
+        false => payload.redacted_info_data()?,
+    };
+    let encoded_data: String = payload
+        .encoded_data
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    for (name, value) in [
+        ("token", payload.token.as_str()),
+        ("hmd5", payload.hmd5.as_str()),
+        ("info data", info_data.as_str()),
+        ("xencode", encoded_data.as_str()),
+        ("info", payload.info.as_str()),
+        ("chksum data", payload.chksum_data.as_str()),
+        ("chksum", payload.chksum.as_str()),
+    ] {
+        println!(
+            "  {} {}",
+            format!("{:<12}", name).if_supports_color(Stdout, |t| t.dimmed()),
+            value
+        );
+    }
+    println!(
+        "  {} {}",
+        format!("{:<12}", "url").if_supports_color(Stdout, |t| t.dimmed()),
+        payload.full_url()?
+    );
     Ok(())
 }
 
 async fn srun_logout(
-    srun_client: &SrunClient,
+    srun_client: &mut SrunClient<CliTransport>,
     client_args: &ClientArgs,
+    interactive: bool,
     verbose: bool,
 ) -> Result<()> {
+    // logging out a session on another device (e.g., left online on a lab PC), confirm first
+    if let (Some(ip), false) = (client_args.ip, client_args.yes) {
+        let local_state =
+            get_login_state(&srun_client.http_client, &srun_client.portal, None, false).await?;
+        if local_state.online_ip != ip {
+            if !interactive {
+                return Err(MissingConfirmation(ip).into());
+            }
+            let login_state = srun_client.fetch_login_state(false).await?;
+            let reply = rprompt::prompt_reply(format!(
+                "{} {} is not this device ({}), log it out {}? [y/N] ",
+                "-> ".if_supports_color(Stdout, |t| t.dimmed()),
+                ip.to_string().if_supports_color(Stdout, |t| t.underline()),
+                local_state.online_ip,
+                format!("({})", login_state.user_name.unwrap_or_default())
+                    .if_supports_color(Stdout, |t| t.dimmed()),
+            ))
+            .with_context(|| "failed to read confirmation")?;
+            if !matches!(reply.trim().to_lowercase().as_str(), "y" | "yes") {
+                bail!("logout of {} cancelled", ip);
+            }
+        }
+    }
+
     let resp = srun_client.logout(client_args.force, verbose).await?;
     match resp.error.as_str() {
         "ok" | "logout_ok" => println!(
@@ -195,3 +563,48 @@ async fn srun_logout(
     };
     Ok(())
 }
+
+async fn srun_doctor(
+    http_client: CliTransport,
+    doctor_args: &DoctorArgs,
+    source_ip: Option<IpAddr>,
+) -> Result<()> {
+    println!(
+        "{} diagnosing connectivity to {}",
+        "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+        SRUN_PORTAL.if_supports_color(Stdout, |t| t.underline())
+    );
+
+    let srun_client = SrunClient::builder().build_with(http_client);
+    let checks = doctor::diagnose(srun_client, &doctor_args.config, source_ip).await;
+    for check in &checks {
+        let mark = match check.status {
+            Status::Pass => "✓".if_supports_color(Stdout, |t| t.green()).to_string(),
+            Status::Warn => "!".if_supports_color(Stdout, |t| t.yellow()).to_string(),
+            Status::Fail => "✗".if_supports_color(Stdout, |t| t.red()).to_string(),
+            Status::Skip => "-".if_supports_color(Stdout, |t| t.dimmed()).to_string(),
+        };
+        println!(
+            "  {} {:<16} {}",
+            mark,
+            check.name,
+            check
+                .detail
+                .replace('\n', "\n                     ")
+                .if_supports_color(Stdout, |t| t.dimmed())
+        );
+        if let Some(hint) = &check.hint {
+            println!(
+                "                     {}: {}",
+                "tip".if_supports_color(Stdout, |t| t.green()),
+                hint
+            );
+        }
+    }
+
+    let failed = checks.iter().filter(|c| c.status == Status::Fail).count();
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use crate::transport::ReqwestTransport;
+
+use std::fs;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::net::UdpSocket;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use owo_colors::OwoColorize;
+use owo_colors::Stream::Stdout;
+
+/// A public IPv6 address used to find the outbound IPv6 address of this device (CERNET DNS)
+pub const IPV6_ROUTE_TEST: &str = "[2001:da8::666]:53";
+
+/// Get the local address this device uses to reach `addr` (`host:port`)
+///
+/// No packets are sent, connecting a UDP socket only makes the OS pick a route and source address.
+pub fn get_local_ip(addr: &str) -> Option<IpAddr> {
+    let target = addr.to_socket_addrs().ok()?.next()?;
+    let socket = match target {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").ok()?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0").ok()?,
+    };
+    socket.connect(target).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    match ip.is_unspecified() | ip.is_loopback() {
+        true => None,
+        false => Some(ip),
+    }
+}
+
+/// Get the IPv6 address this device uses to reach the outside world
+pub fn get_local_ipv6() -> Option<IpAddr> {
+    get_local_ip(IPV6_ROUTE_TEST)
+}
+
+/// Get the IPv4 default gateway from the routing table (only supported on Linux)
+///
+/// Only default routes through `interface` are considered if specified.
+pub fn get_default_gateway(interface: Option<&str>) -> Option<IpAddr> {
+    // each line of /proc/net/route is `Iface Destination Gateway Flags ...`, where addresses are
+    // hex encoded in host byte order, and the default route has a destination of 0
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [iface, "00000000", gateway, ..] if interface.is_none_or(|i| i == *iface) => {
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some(IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Get the address of a network interface by its name (e.g., `eth0`), IPv4 preferred
+pub fn get_interface_ip(interface: &str) -> Result<IpAddr> {
+    let addrs = if_addrs::get_if_addrs().with_context(|| "failed to list network interfaces")?;
+    let mut ips: Vec<IpAddr> = addrs
+        .iter()
+        .filter(|iface| iface.name == interface)
+        .map(|iface| iface.ip())
+        .collect();
+
+    // IPv4 addresses come first, then IPv6 addresses that are not link-local
+    ips.sort_by_key(|ip| !ip.is_ipv4());
+    ips.into_iter()
+        .find(|ip| match ip {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(ip6) => (ip6.segments()[0] & 0xffc0) != 0xfe80,
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "no usable address found on network interface `{}`",
+                interface.if_supports_color(Stdout, |t| t.underline())
+            )
+        })
+}
+
+/// Get the name of the network interface that has `ip` as one of its addresses
+pub fn get_ip_interface(ip: IpAddr) -> Option<String> {
+    if_addrs::get_if_addrs()
+        .ok()?
+        .into_iter()
+        .find(|iface| iface.ip() == ip)
+        .map(|iface| iface.name)
+}
+
+/// Resolve the local address to bind to, either from an interface name or an explicit address
+///
+/// An explicit `source_ip` takes priority over `interface`.
+pub fn resolve_source_ip(
+    interface: &Option<String>,
+    source_ip: &Option<IpAddr>,
+) -> Result<Option<IpAddr>> {
+    match (source_ip, interface) {
+        (Some(ip), _) => Ok(Some(*ip)),
+        (None, Some(interface)) => get_interface_ip(interface).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Build the reusable http client, bound to `source_ip` if specified
+pub fn build_http_client(source_ip: Option<IpAddr>) -> Result<ReqwestTransport> {
+    ReqwestTransport::new(|builder| builder.local_address(source_ip))
+}
@@ -0,0 +1,440 @@
+use crate::client::SrunLoginState;
+use crate::detect::find_page_redirect;
+use crate::detect::resolve;
+use crate::scheme::SrunScheme;
+use crate::transport::HttpTransport;
+
+use std::fmt;
+
+use owo_colors::OwoColorize;
+use owo_colors::Stream::Stdout;
+use serde::Serialize;
+
+/// Scripts fetched from the landing page at most, the portal's own JS is usually among the first
+const MAX_SCRIPTS: usize = 8;
+
+/// Bytes around `enc_ver` searched for `n` and `type`, which are defined alongside it
+const PARAMS_WINDOW: usize = 1000;
+
+/// `srun_ver` (offline) and `sysver` (online) prefixes of the release `bitsrun` is tested against,
+/// whose portal is not probed any further
+const TESTED_SRUN_VERS: &[&str] = &["SRunCGIAuthIntfSvr V1.18"];
+const TESTED_SYSVERS: &[&str] = &["1.01."];
+
+/// What the portal reports and serves about its SRUN release
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Capabilities {
+    /// `srun_ver` from `rad_user_info`, reported when offline
+    pub srun_ver: Option<String>,
+    /// `sysver` from `rad_user_info`, reported when online
+    pub sysver: Option<String>,
+    /// `enc_ver` used by the portal's JS (e.g., `srun_bx1`)
+    pub enc_ver: Option<String>,
+    /// `n` parameter used by the portal's JS
+    pub n: Option<String>,
+    /// `type` parameter used by the portal's JS
+    pub srun_type: Option<String>,
+    /// Parameters found with conflicting values (e.g., `n` of a loop next to the real one), which
+    /// are left to their defaults
+    pub ambiguous: Vec<String>,
+    /// Whether the portal's JS logs in through `/cgi-bin/srun_portal`
+    pub cgi_portal: bool,
+    /// Whether the portal's JS logs in through `/v1/srun_portal_sso`
+    pub sso_portal: bool,
+    /// URL of the landing page and scripts that were probed
+    pub probed: Vec<String>,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".into());
+        write!(
+            f,
+            "srun_ver {}, sysver {}, enc_ver {}, n {}, type {}",
+            show(&self.srun_ver),
+            show(&self.sysver),
+            show(&self.enc_ver),
+            show(&self.n),
+            show(&self.srun_type)
+        )?;
+        if self.sso_portal {
+            write!(f, ", sso portal")?;
+        }
+        if !self.ambiguous.is_empty() {
+            write!(f, ", ambiguous {}", self.ambiguous.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Capabilities {
+    /// Whether the portal reports the release `bitsrun` is tested against
+    pub fn is_tested(&self) -> bool {
+        let matches = |value: &Option<String>, tested: &[&str]| {
+            value
+                .as_deref()
+                .is_some_and(|value| tested.iter().any(|t| value.starts_with(t)))
+        };
+        matches(&self.srun_ver, TESTED_SRUN_VERS) || matches(&self.sysver, TESTED_SYSVERS)
+    }
+
+    /// The login scheme matching the probed capabilities, BIT's scheme where nothing unambiguous
+    /// was found
+    pub fn scheme(&self) -> SrunScheme {
+        let mut scheme = SrunScheme::default();
+        if let Some(enc_ver) = &self.enc_ver {
+            scheme.enc_ver = enc_ver.clone();
+        }
+        if let Some(n) = &self.n {
+            scheme.n = n.clone();
+        }
+        if let Some(srun_type) = &self.srun_type {
+            scheme.srun_type = srun_type.clone();
+        }
+        if self.sso_portal & !self.cgi_portal {
+            scheme.endpoint = "/v1/srun_portal_sso".into();
+        }
+        scheme
+    }
+
+    /// How the portal differs from the release `bitsrun` is tested against (BIT's), if at all
+    pub fn untested(&self) -> Vec<String> {
+        if self.is_tested() {
+            return Vec::new();
+        }
+        let tested = SrunScheme::default();
+        let scheme = self.scheme();
+        let mut untested = Vec::new();
+        if let Some(version) = self.srun_ver.as_ref().or(self.sysver.as_ref()) {
+            untested.push(format!("release `{}`", version));
+        }
+        for (name, value, expected) in [
+            ("enc_ver", &scheme.enc_ver, &tested.enc_ver),
+            ("n", &scheme.n, &tested.n),
+            ("type", &scheme.srun_type, &tested.srun_type),
+            ("endpoint", &scheme.endpoint, &tested.endpoint),
+        ] {
+            if value != expected {
+                untested.push(format!("{} `{}` (tested `{}`)", name, value, expected));
+            }
+        }
+        for ambiguous in &self.ambiguous {
+            untested.push(format!("ambiguous {}, kept the default", ambiguous));
+        }
+        untested
+    }
+
+    /// A warning if the portal differs from the tested release
+    pub fn untested_warning(&self) -> Option<String> {
+        let untested = self.untested();
+        if untested.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "portal uses an untested {}, login may fail, specify `scheme` in the config file if it does",
+            untested.join(", ")
+        ))
+    }
+}
+
+/// A single value, or `Err` with all of them if they conflict
+fn unambiguous(mut values: Vec<String>) -> Result<Option<String>, Vec<String>> {
+    values.sort();
+    values.dedup();
+    match values.len() {
+        0 => Ok(None),
+        1 => Ok(values.pop()),
+        _ => Err(values),
+    }
+}
+
+/// Find all numbers assigned to `name` in JS, e.g., `n = 200`, `n: "200"` or `"n":200`
+fn find_assignments(text: &str, name: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut offset = 0;
+    while let Some(i) = text[offset..].find(name) {
+        let start = offset + i;
+        let end = start + name.len();
+        offset = end;
+
+        // `name` should be a whole identifier, optionally quoted
+        let before = text[..start].chars().next_back();
+        if before.is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '$' | '.')) {
+            continue;
+        }
+        let rest = text[end..].trim_start_matches(['"', '\'']).trim_start();
+        let Some(rest) = rest.strip_prefix([':', '=']) else {
+            continue;
+        };
+        if rest.starts_with('=') {
+            continue; // comparison, not assignment
+        }
+        let value: String = rest
+            .trim_start()
+            .trim_start_matches(['"', '\''])
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
+            .collect();
+        if !value.is_empty() {
+            values.push(value);
+        }
+    }
+    values
+}
+
+/// Find the `enc_ver` used by the portal (e.g., `srun_bx1`), and the code around its first use
+fn find_enc_ver(text: &str) -> Option<(Vec<String>, &str)> {
+    let start = text.find("srun_bx")?;
+    let enc_vers = text
+        .match_indices("srun_bx")
+        .map(|(i, _)| {
+            text[i..]
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                .collect()
+        })
+        .collect();
+
+    let mut window_start = start.saturating_sub(PARAMS_WINDOW);
+    while !text.is_char_boundary(window_start) {
+        window_start -= 1;
+    }
+    let mut window_end = (start + PARAMS_WINDOW).min(text.len());
+    while !text.is_char_boundary(window_end) {
+        window_end += 1;
+    }
+    Some((enc_vers, &text[window_start..window_end]))
+}
+
+/// Scrape the scheme parameters from the landing page and scripts, only adopting unambiguous ones
+fn scrape_pages(caps: &mut Capabilities, pages: &[String]) {
+    let mut enc_vers = Vec::new();
+    let (mut ns, mut types) = (Vec::new(), Vec::new());
+
+    // `n` and `type` are too common to be searched in the whole page (e.g., in jQuery)
+    for page in pages {
+        if let Some((found, code)) = find_enc_ver(page) {
+            enc_vers.extend(found);
+            ns.extend(find_assignments(code, "n"));
+            types.extend(find_assignments(code, "type"));
+        }
+        caps.cgi_portal |= page.contains("/cgi-bin/srun_portal");
+        caps.sso_portal |= page.contains("srun_portal_sso");
+    }
+
+    for (name, values, field) in [
+        ("enc_ver", enc_vers, &mut caps.enc_ver),
+        ("n", ns, &mut caps.n),
+        ("type", types, &mut caps.srun_type),
+    ] {
+        match unambiguous(values) {
+            Ok(value) => *field = value,
+            Err(values) => caps
+                .ambiguous
+                .push(format!("{} ({})", name, values.join(", "))),
+        }
+    }
+}
+
+/// Find the `src` of all `<script>` tags in a page
+fn find_scripts(body: &str) -> Vec<String> {
+    let lower = body.to_ascii_lowercase();
+    let mut scripts = Vec::new();
+    let mut offset = 0;
+    while let Some(i) = lower[offset..].find("<script") {
+        let start = offset + i;
+        let end = lower[start..].find('>').map_or(lower.len(), |i| start + i);
+        offset = end;
+        if let Some(src) = lower[start..end].find("src=") {
+            let src = &body[start + src + 4..end];
+            let quote = src.chars().next().filter(|c| matches!(c, '"' | '\''));
+            let src = match quote {
+                Some(quote) => src[1..].split(quote).next(),
+                None => src.split_whitespace().next(),
+            };
+            if let Some(src) = src.filter(|src| !src.is_empty()) {
+                scripts.push(src.to_string());
+            }
+        }
+    }
+    scripts
+}
+
+/// Probe the capabilities of the portal from its login state, landing page and scripts
+///
+/// Probing never fails, anything that can't be fetched or found is left unknown. Portals that
+/// report the tested release are not probed any further, and scripts are only fetched until the
+/// one computing login requests is found.
+pub async fn probe_capabilities<T: HttpTransport>(
+    client: &T,
+    portal: &str,
+    login_state: Option<&SrunLoginState>,
+    verbose: bool,
+) -> Capabilities {
+    let mut caps = Capabilities {
+        srun_ver: login_state.and_then(|s| s.srun_ver.clone()),
+        sysver: login_state.and_then(|s| s.sysver.clone()),
+        ..Default::default()
+    };
+
+    // the portal root redirects to the login page of the current network
+    let mut pages = Vec::new();
+    let resp = match caps.is_tested() {
+        true => None,
+        false => client.get(portal, &[]).await.ok(),
+    };
+    if let Some(mut resp) = resp {
+        if let Some(target) = find_page_redirect(&resp.body) {
+            if let Ok(redirected) = client.get(&resolve(&resp.url, &target), &[]).await {
+                resp = redirected;
+            }
+        }
+        caps.probed.push(resp.url.clone());
+        let inline = resp.body.contains("srun_bx");
+        pages.push(resp.body.clone());
+        if !inline {
+            for script in find_scripts(&resp.body).iter().take(MAX_SCRIPTS) {
+                let url = resolve(&resp.url, script);
+                if let Ok(script) = client.get(&url, &[]).await {
+                    caps.probed.push(url);
+                    let found = script.body.contains("srun_bx");
+                    pages.push(script.body);
+                    if found {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    scrape_pages(&mut caps, &pages);
+
+    if verbose {
+        println!(
+            "{} probed portal capabilities: {}{}",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+            caps,
+            caps.probed
+                .iter()
+                .map(|url| format!("\n  - {}", url))
+                .collect::<String>()
+                .if_supports_color(Stdout, |t| t.dimmed())
+        );
+    }
+    caps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::ScriptedTransport;
+
+    const INDEX: &str = include_str!("../tests/fixtures/portal/index.html");
+    const PORTAL_JS: &str = include_str!("../tests/fixtures/portal/jquery.srun.portal.js");
+    const PORTAL_MIN_JS: &str = include_str!("../tests/fixtures/portal/portal.min.js");
+
+    fn scraped(pages: &[&str]) -> Capabilities {
+        let mut caps = Capabilities::default();
+        scrape_pages(
+            &mut caps,
+            &pages.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        );
+        caps
+    }
+
+    #[test]
+    fn find_scripts_in_landing_page() {
+        assert_eq!(
+            find_scripts(INDEX),
+            [
+                "./static/js/jquery.min.js",
+                "./static/js/jquery.srun.portal.js?v=1.18",
+                "./static/js/portal.main.js"
+            ]
+        );
+    }
+
+    #[test]
+    fn find_assignments_skips_comparisons_and_members() {
+        assert_eq!(find_assignments("n = 200, type: \"1\"", "n"), ["200"]);
+        assert_eq!(find_assignments("\"type\":1", "type"), ["1"]);
+        assert!(find_assignments("if(n==1){a.n=3;sn=4}", "n").is_empty());
+    }
+
+    #[test]
+    fn scrape_readable_portal_js() {
+        let (enc_vers, code) = find_enc_ver(PORTAL_JS).unwrap();
+        assert_eq!(enc_vers, ["srun_bx1"]);
+        assert_eq!(find_assignments(code, "n"), ["200"]);
+        assert_eq!(find_assignments(code, "type"), ["1"]);
+
+        let caps = scraped(&[INDEX, PORTAL_JS]);
+        assert_eq!(caps.n.as_deref(), Some("200"));
+        assert!(caps.cgi_portal && !caps.sso_portal);
+        assert!(caps.ambiguous.is_empty());
+        assert!(caps.untested().is_empty());
+    }
+
+    #[test]
+    fn scrape_minified_portal_js_keeps_ambiguous_defaults() {
+        // `for(var n=0;...)` sits next to the real `n:200`
+        let caps = scraped(&[PORTAL_MIN_JS]);
+        assert_eq!(caps.n, None);
+        assert_eq!(caps.ambiguous, ["n (0, 200)"]);
+        assert_eq!(caps.srun_type.as_deref(), Some("1"));
+        assert_eq!(caps.scheme().n, SrunScheme::default().n);
+        assert!(caps
+            .untested_warning()
+            .unwrap()
+            .contains("ambiguous n (0, 200)"));
+    }
+
+    #[test]
+    fn untested_warning_follows_the_release() {
+        let caps = Capabilities {
+            srun_ver: Some("SRunCGIAuthIntfSvr V1.18 B20211105".into()),
+            n: Some("100".into()),
+            ..Default::default()
+        };
+        assert!(caps.is_tested());
+        assert_eq!(caps.untested_warning(), None);
+
+        let caps = Capabilities {
+            srun_ver: Some("SRunCGIAuthIntfSvr V1.20 B20230405".into()),
+            ..Default::default()
+        };
+        assert!(!caps.is_tested());
+        assert!(caps
+            .untested_warning()
+            .unwrap()
+            .contains("release `SRunCGIAuthIntfSvr V1.20 B20230405`"));
+    }
+
+    #[tokio::test]
+    async fn probe_skips_tested_releases() {
+        let transport = ScriptedTransport::new();
+        let login_state = serde_json::from_str::<SrunLoginState>(
+            r#"{"error":"not_online_error","online_ip":"10.62.1.2","client_ip":"10.62.1.2","srun_ver":"SRunCGIAuthIntfSvr V1.18 B20211105"}"#,
+        )
+        .unwrap();
+        let caps =
+            probe_capabilities(&transport, "http://10.0.0.55", Some(&login_state), false).await;
+        assert!(transport.requests().is_empty());
+        assert_eq!(caps.scheme().n, SrunScheme::default().n);
+    }
+
+    #[tokio::test]
+    async fn probe_stops_at_the_portal_script() {
+        let portal = "http://10.0.0.55/srun_portal_pc?ac_id=1";
+        let transport = ScriptedTransport::new()
+            .respond(portal, INDEX)
+            .respond("http://10.0.0.55/static/js/jquery.min.js", "/*! jQuery */")
+            .respond(
+                "http://10.0.0.55/static/js/jquery.srun.portal.js?v=1.18",
+                PORTAL_JS,
+            );
+        let caps = probe_capabilities(&transport, "http://10.0.0.55", None, false).await;
+        assert_eq!(transport.requests().len(), 3);
+        assert_eq!(caps.enc_ver.as_deref(), Some("srun_bx1"));
+        assert!(caps.untested().is_empty());
+    }
+}
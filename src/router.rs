@@ -0,0 +1,389 @@
+use crate::client::get_login_state;
+use crate::client::SrunClient;
+use crate::client::SrunLoginState;
+use crate::client::SrunPortalResponse;
+use crate::client::SRUN_PORTAL;
+use crate::config;
+use crate::net;
+use crate::probe::probe_capabilities;
+use crate::scheme::SrunScheme;
+use crate::secret::Secret;
+use crate::tables::print_managed_states;
+
+use crate::transport::HttpTransport;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use log::info;
+use log::warn;
+use owo_colors::OwoColorize;
+use owo_colors::Stream::Stdout;
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use tokio::signal::ctrl_c;
+use tokio::time::Duration;
+
+/// A LAN host that is logged in on behalf of by the router
+#[derive(Debug, Deserialize)]
+pub struct ManagedHost {
+    pub ip: IpAddr,
+    pub username: String,
+    // not required for registered dumb terminals
+    #[serde(default)]
+    pub password: Secret,
+    #[serde(default)]
+    pub dm: bool,
+    // detected from the router itself if not specified, hosts may sit in another area
+    pub ac_id: Option<String>,
+    pub os: Option<String>,
+    pub name: Option<String>,
+}
+
+/// Fail with a summary if any of the managed hosts failed
+fn fail_on_hosts(action: &str, failed: &[IpAddr], total: usize) -> Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let failed: Vec<String> = failed.iter().map(IpAddr::to_string).collect();
+    bail!(
+        "failed to {} {} of {} hosts ({})",
+        action,
+        failed.len(),
+        total,
+        failed.join(", ")
+    )
+}
+
+/// Router mode, where one instance manages the logins of multiple LAN hosts
+#[derive(Debug, Deserialize)]
+pub struct SrunRouter {
+    hosts: Vec<ManagedHost>,
+    // polls every 1 hour by default
+    poll_interval: Option<u64>,
+    // network interface or local address to bind portal requests to
+    interface: Option<String>,
+    source_ip: Option<IpAddr>,
+    // login scheme of the portal, probed once for all hosts if not specified
+    scheme: Option<SrunScheme>,
+    // ac_id discovered for hosts that don't specify one, reused until a login with it fails
+    #[serde(skip)]
+    ac_ids: Mutex<BTreeMap<IpAddr, String>>,
+}
+
+impl SrunRouter {
+    pub fn new(config_path: Option<String>) -> Result<SrunRouter> {
+        let finalized_cfg = config::validate_config_file(&config_path)?;
+
+        // in router mode, all managed hosts must be listed under `hosts` in the config file
+        let router_cfg_str = fs::read_to_string(&finalized_cfg)
+            .map(Zeroizing::new)
+            .with_context(|| {
+                format!(
+                    "failed to read config file `{}`",
+                    &finalized_cfg.if_supports_color(Stdout, |t| t.underline())
+                )
+            })?;
+        let router_cfg =
+            serde_json::from_str::<SrunRouter>(&router_cfg_str).with_context(|| {
+                format!(
+                    "failed to parse config file `{}`",
+                    &finalized_cfg.if_supports_color(Stdout, |t| t.underline())
+                )
+            })?;
+
+        if router_cfg.hosts.is_empty() {
+            bail!(
+                "no managed hosts found under `{}` in config file `{}`",
+                "hosts".if_supports_color(Stdout, |t| t.cyan()),
+                &finalized_cfg.if_supports_color(Stdout, |t| t.underline())
+            );
+        }
+
+        Ok(router_cfg)
+    }
+
+    /// The local address to bind portal requests to, where `source_ip` overrides `interface` and
+    /// `source_ip` in config
+    pub fn source_ip(&self, source_ip: Option<IpAddr>) -> Result<Option<IpAddr>> {
+        match source_ip {
+            Some(ip) => Ok(Some(ip)),
+            None => net::resolve_source_ip(&self.interface, &self.source_ip),
+        }
+    }
+
+    /// The login scheme from the config file, or probed from the portal, with a warning if untested
+    async fn login_scheme<T: HttpTransport>(
+        &self,
+        http_client: &T,
+        verbose: bool,
+    ) -> (SrunScheme, Option<String>) {
+        match &self.scheme {
+            Some(scheme) => (scheme.clone(), None),
+            None => {
+                // the release reported to the router itself tells whether to probe any further
+                let login_state = get_login_state(http_client, SRUN_PORTAL, None, false)
+                    .await
+                    .ok();
+                let caps =
+                    probe_capabilities(http_client, SRUN_PORTAL, login_state.as_ref(), verbose)
+                        .await;
+                (caps.scheme(), caps.untested_warning())
+            }
+        }
+    }
+
+    fn client_for<'a, T: HttpTransport + Sync>(
+        &self,
+        host: &ManagedHost,
+        http_client: &'a T,
+        scheme: &SrunScheme,
+    ) -> SrunClient<&'a T> {
+        SrunClient::builder()
+            .username(host.username.clone())
+            .password(host.password.clone())
+            .ip(host.ip)
+            .ac_id(host.ac_id.clone().or_else(|| self.discovered_ac_id(host)))
+            .dm(host.dm)
+            .device(host.os.clone(), host.name.clone())
+            .scheme(scheme.clone())
+            .build_with(http_client)
+    }
+
+    fn discovered_ac_id(&self, host: &ManagedHost) -> Option<String> {
+        self.ac_ids.lock().unwrap().get(&host.ip).cloned()
+    }
+
+    /// Login a managed host, remembering its discovered ac_id unless the portal rejects the login
+    async fn login_host<T: HttpTransport + Sync>(
+        &self,
+        host: &ManagedHost,
+        http_client: &T,
+        scheme: &SrunScheme,
+        force: bool,
+        verbose: bool,
+    ) -> Result<SrunPortalResponse> {
+        let mut srun = self.client_for(host, http_client, scheme);
+        let resp = srun.login(force, verbose).await;
+        let mut ac_ids = self.ac_ids.lock().unwrap();
+        match (&resp, srun.ac_id) {
+            (Ok(resp), _) if resp.error != "ok" => ac_ids.remove(&host.ip),
+            (_, Some(ac_id)) if host.ac_id.is_none() => ac_ids.insert(host.ip, ac_id),
+            _ => None,
+        };
+        resp
+    }
+
+    /// Login all managed hosts, failing if any of them fails
+    pub async fn login<T: HttpTransport + Sync>(
+        &self,
+        http_client: T,
+        force: bool,
+        verbose: bool,
+    ) -> Result<()> {
+        let (scheme, warning) = self.login_scheme(&http_client, verbose).await;
+        if let Some(warning) = warning {
+            println!(
+                "{} {}",
+                "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                warning
+            );
+        }
+        let mut failed = Vec::new();
+        for host in &self.hosts {
+            let resp = self
+                .login_host(host, &http_client, &scheme, force, verbose)
+                .await;
+            if !matches!(&resp, Ok(resp) if resp.error == "ok") {
+                failed.push(host.ip);
+            }
+            match resp {
+                Ok(resp) if resp.error == "ok" => println!(
+                    "{} {} {} logged in",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.bright_green()),
+                    host.ip
+                        .to_string()
+                        .if_supports_color(Stdout, |t| t.underline()),
+                    format!("({})", host.username).if_supports_color(Stdout, |t| t.dimmed())
+                ),
+                Ok(resp) => println!(
+                    "{} {} failed to login, {} {}",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.red()),
+                    host.ip
+                        .to_string()
+                        .if_supports_color(Stdout, |t| t.underline()),
+                    resp.error,
+                    format!("({})", resp.error_msg).if_supports_color(Stdout, |t| t.dimmed())
+                ),
+                Err(e) => println!(
+                    "{} {} failed to login, {}",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.red()),
+                    host.ip
+                        .to_string()
+                        .if_supports_color(Stdout, |t| t.underline()),
+                    e
+                ),
+            }
+        }
+        fail_on_hosts("login", &failed, self.hosts.len())
+    }
+
+    /// Logout all managed hosts, failing if any of them fails
+    pub async fn logout<T: HttpTransport + Sync>(
+        &self,
+        http_client: T,
+        force: bool,
+        verbose: bool,
+    ) -> Result<()> {
+        // logging out sends no payload, the scheme is irrelevant
+        let scheme = self.scheme.clone().unwrap_or_default();
+        let mut failed = Vec::new();
+        for host in &self.hosts {
+            let resp = self
+                .client_for(host, &http_client, &scheme)
+                .logout(force, verbose)
+                .await;
+            if !matches!(&resp, Ok(resp) if matches!(resp.error.as_str(), "ok" | "logout_ok")) {
+                failed.push(host.ip);
+            }
+            match resp {
+                Ok(resp) if matches!(resp.error.as_str(), "ok" | "logout_ok") => println!(
+                    "{} {} logged out",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.green()),
+                    host.ip
+                        .to_string()
+                        .if_supports_color(Stdout, |t| t.underline())
+                ),
+                Ok(resp) => println!(
+                    "{} {} failed to logout, {} {}",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.red()),
+                    host.ip
+                        .to_string()
+                        .if_supports_color(Stdout, |t| t.underline()),
+                    resp.error,
+                    format!("({})", resp.error_msg).if_supports_color(Stdout, |t| t.dimmed())
+                ),
+                Err(e) => println!(
+                    "{} {} failed to logout, {}",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.red()),
+                    host.ip
+                        .to_string()
+                        .if_supports_color(Stdout, |t| t.underline()),
+                    e
+                ),
+            }
+        }
+        fail_on_hosts("logout", &failed, self.hosts.len())
+    }
+
+    /// Print the login state of all managed hosts
+    pub async fn status<T: HttpTransport>(&self, http_client: T, verbose: bool) -> Result<()> {
+        println!(
+            "{} managing {} hosts",
+            "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+            self.hosts.len()
+        );
+
+        let mut states: Vec<(&ManagedHost, Result<SrunLoginState>)> = Vec::new();
+        for host in &self.hosts {
+            // the portal may answer with the state of another address (e.g., the router itself)
+            let state = get_login_state(&http_client, SRUN_PORTAL, Some(host.ip), verbose)
+                .await
+                .and_then(|state| match state.online_ip == host.ip {
+                    true => Ok(state),
+                    false => Err(anyhow!(
+                        "portal reported the state of {} instead",
+                        state.online_ip
+                    )),
+                });
+            if let (true, Err(e)) = (verbose, &state) {
+                println!(
+                    "{} {} status unknown, {}",
+                    "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                    host.ip,
+                    e
+                );
+            }
+            states.push((host, state));
+        }
+        print_managed_states(&states);
+        Ok(())
+    }
+
+    /// Poll the server with login requests to keep all managed hosts alive
+    pub async fn start<T: HttpTransport + Sync>(&self, http_client: T) -> Result<()> {
+        // set logger to INFO level by default
+        pretty_env_logger::formatted_builder()
+            .filter_level(log::LevelFilter::Info)
+            .init();
+
+        // set default polling intervals every 1 hour
+        let poll_interval = self.poll_interval.unwrap_or(3600);
+
+        // warn if polling interval is too short
+        if poll_interval < 60 * 10 {
+            warn!("polling interval is too short, please set it to at least 10 minutes (600s)");
+        }
+
+        let (scheme, warning) = self.login_scheme(&http_client, false).await;
+        if let Some(warning) = warning {
+            warn!("{}", warning);
+        }
+
+        let mut srun_ticker = tokio::time::interval(Duration::from_secs(poll_interval));
+        info!(
+            "starting router daemon ({} hosts) with polling interval={}s",
+            self.hosts.len(),
+            poll_interval,
+        );
+
+        loop {
+            tokio::select! {
+                _ = srun_ticker.tick() => {
+                    for host in &self.hosts {
+                        self.keep_alive(host, &http_client, &scheme).await;
+                    }
+                }
+                _ = ctrl_c() => {
+                    info!("router: gracefully exiting");
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn keep_alive<T: HttpTransport + Sync>(
+        &self,
+        host: &ManagedHost,
+        http_client: &T,
+        scheme: &SrunScheme,
+    ) {
+        let resp = self
+            .login_host(host, http_client, scheme, true, false)
+            .await;
+        match resp {
+            Ok(resp) => match resp.error.as_str() {
+                "ok" => info!(
+                    "{} ({}): login success, {}",
+                    host.ip,
+                    host.username,
+                    resp.suc_msg.unwrap_or_default()
+                ),
+                _ => warn!(
+                    "{} ({}): login failed, {}",
+                    host.ip, host.username, resp.error
+                ),
+            },
+            Err(e) => warn!("{} ({}): login failed: {}", host.ip, host.username, e),
+        }
+    }
+}
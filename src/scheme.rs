@@ -0,0 +1,183 @@
+use crate::client::LoginPayload;
+use crate::client::SRUN_N;
+use crate::client::SRUN_TYPE;
+use crate::secret::Secret;
+use crate::xencode::fkbase64;
+use crate::xencode::xencode;
+use crate::xencode::SRBX1_PREFIX;
+
+use std::fmt;
+use std::net::IpAddr;
+
+use anyhow::Result;
+use hmac::Hmac;
+use hmac::Mac;
+use md5::Digest;
+use md5::Md5;
+use serde::Deserialize;
+use serde::Serialize;
+use sha1::Sha1;
+
+/// The JSON encrypted into `info`, fields in the order the portal expects them
+///
+/// Serialized from borrowed fields, so that the password is not copied into intermediate values.
+#[derive(Serialize)]
+struct InfoData<'a> {
+    acid: &'a str,
+    enc_ver: &'a str,
+    ip: &'a str,
+    password: &'a str,
+    username: &'a str,
+}
+
+/// What a login scheme computes the login request from
+///
+/// The password stays a `Secret`, so that debug output of the input never reveals it.
+#[derive(Debug, Clone)]
+pub struct LoginInput<'a> {
+    pub portal: &'a str,
+    pub username: &'a str,
+    pub password: &'a Secret,
+    pub ip: IpAddr,
+    pub ac_id: &'a str,
+}
+
+/// How login requests are computed from the challenge, which differs between SRUN releases
+///
+/// `SrunScheme` covers the `/cgi-bin/srun_portal` family, with BIT's parameters by default.
+/// Implement this trait for flows that differ more than its options allow.
+pub trait LoginScheme: fmt::Debug + Send + Sync {
+    /// Compute the login request from a challenge `token`
+    fn login_payload(&self, input: &LoginInput<'_>, token: &str) -> Result<LoginPayload>;
+}
+
+/// How the password is hashed into the `password` parameter (and the checksum)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordHash {
+    /// HMAC-MD5 of an empty message keyed with the token, which is what BIT accepts
+    #[default]
+    HmacToken,
+    /// HMAC-MD5 of the password keyed with the token, as the official JS portal computes it
+    HmacPassword,
+}
+
+/// Login scheme of the `srun_portal` endpoint family, configurable for other SRUN releases
+///
+/// Every field is optional in the config file, and defaults to BIT's scheme, e.g.:
+///
+/// ```json
+/// "scheme": { "enc_ver": "srun_bx1", "n": "200", "type": "1", "password_hash": "hmac_password" }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SrunScheme {
+    /// `enc_ver` encrypted into `info`
+    pub enc_ver: String,
+    /// The `n` parameter
+    pub n: String,
+    /// The `type` parameter
+    #[serde(rename = "type")]
+    pub srun_type: String,
+    pub password_hash: PasswordHash,
+    /// Whether the `password` parameter is prefixed with `{MD5}`
+    pub md5_prefix: bool,
+    /// Path of the login endpoint, e.g., `/v1/srun_portal_sso` on some newer releases
+    pub endpoint: String,
+}
+
+impl Default for SrunScheme {
+    fn default() -> Self {
+        SrunScheme {
+            enc_ver: "srun_bx1".into(),
+            n: SRUN_N.into(),
+            srun_type: SRUN_TYPE.into(),
+            password_hash: PasswordHash::HmacToken,
+            md5_prefix: true,
+            endpoint: "/cgi-bin/srun_portal".into(),
+        }
+    }
+}
+
+impl LoginScheme for SrunScheme {
+    fn login_payload(&self, input: &LoginInput<'_>, token: &str) -> Result<LoginPayload> {
+        let ip = input.ip.to_string();
+
+        // construct checksum and crypto encodings
+        let info_data = Secret::new(serde_json::to_string(&InfoData {
+            acid: input.ac_id,
+            enc_ver: &self.enc_ver,
+            ip: &ip,
+            password: input.password.expose(),
+            username: input.username,
+        })?);
+        let encoded_data = xencode(info_data.expose(), token)?;
+        let info = format!("{}{}", SRBX1_PREFIX, fkbase64(encoded_data.clone()));
+
+        let mut mac = Hmac::<Md5>::new_from_slice(token.as_bytes())?;
+        if self.password_hash == PasswordHash::HmacPassword {
+            mac.update(input.password.expose().as_bytes());
+        }
+        let hmd5 = format!("{:x}", mac.finalize().into_bytes());
+
+        let chksum_data = format!(
+            "{0}{1}{0}{2}{0}{3}{0}{4}{0}{5}{0}{6}{0}{7}",
+            token, input.username, &hmd5, input.ac_id, &ip, &self.n, &self.srun_type, &info
+        );
+        let chksum = {
+            let mut hasher = Sha1::new();
+            hasher.update(&chksum_data);
+            format!("{:x}", hasher.finalize())
+        };
+
+        // construct request params
+        let password = match self.md5_prefix {
+            true => format!("{}{}", "{MD5}", hmd5),
+            false => hmd5.clone(),
+        };
+        let params = vec![
+            ("callback", "jsonp".to_string()),
+            ("action", "login".to_string()),
+            ("username", input.username.to_string()),
+            ("password", password),
+            ("chksum", chksum.clone()),
+            ("info", info.clone()),
+            ("ac_id", input.ac_id.to_string()),
+            ("ip", ip),
+            ("type", self.srun_type.clone()),
+            ("n", self.n.clone()),
+        ];
+
+        Ok(LoginPayload {
+            token: token.to_string(),
+            info_data,
+            encoded_data,
+            info,
+            hmd5,
+            chksum_data,
+            chksum,
+            url: format!("{}{}", input.portal, self.endpoint),
+            params,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_input_debug_redacts_password() {
+        let password = Secret::new("hunter2");
+        let input = LoginInput {
+            portal: "http://10.0.0.55",
+            username: "1120201234",
+            password: &password,
+            ip: "10.62.1.2".parse().unwrap(),
+            ac_id: "1",
+        };
+        let debug = format!("{:?}", input);
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains("[redacted]"));
+    }
+}
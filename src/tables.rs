@@ -1,6 +1,9 @@
 use crate::client::SrunLoginState;
-use crate::config::enumerate_config_paths;
+use crate::config::discover_config_paths;
+use crate::config::ConfigStatus;
+use crate::router::ManagedHost;
 
+use anyhow::Result;
 use chrono::Duration;
 use chrono_humanize::Accuracy::Rough;
 use chrono_humanize::HumanTime;
@@ -13,15 +16,17 @@ use tabled::builder::Builder;
 use tabled::settings::Style;
 use tabled::settings::Width;
 
-/// Print all possible config file paths as a table
+/// Print all possible config file paths as a table, with the status of each one
 ///
 /// # Example output
 ///
-/// ┌──────────┬───────────────────────────────────────────────────────────┐
-/// │ Priority │ Possible config path                                      │
-/// ├──────────┼───────────────────────────────────────────────────────────┤
-/// │ 1        │ C:\Users\{USERNAME}\AppData\Roaming\bitsrun\bit-user.json │
-/// └──────────┴───────────────────────────────────────────────────────────┘
+/// ┌──────────┬──────────────────────────────────────────┬───────────────────────────────────────┐
+/// │ Priority │ Possible Config Path                     │ Status                                │
+/// ├──────────┼──────────────────────────────────────────┼───────────────────────────────────────┤
+/// │ 1        │ /home/user/.config/bitsrun/bit-user.json │ skipped, has too open permissions 644 │
+/// │ 2        │ /home/user/.config/bit-user.json         │ selected                              │
+/// │ 3        │ /etc/xdg/bitsrun/bit-user.json           │ missing                               │
+/// └──────────┴──────────────────────────────────────────┴───────────────────────────────────────┘
 pub fn print_config_paths() {
     println!(
         "{} list of possible config paths",
@@ -29,28 +34,44 @@ pub fn print_config_paths() {
     );
 
     let mut builder = Builder::default();
-    builder.set_header(["Priority", "Possible Config Path"]);
+    builder.set_header(["Priority", "Possible Config Path", "Status"]);
 
-    for (i, path) in enumerate_config_paths().iter().enumerate() {
-        builder.push_record([(i + 1).to_string(), path.into()]);
+    for (i, (path, status)) in discover_config_paths().iter().enumerate() {
+        let status_str = status.to_string();
+        let status_str = match status {
+            ConfigStatus::Selected => status_str
+                .if_supports_color(Stdout, |t| t.green())
+                .to_string(),
+            ConfigStatus::Shadowed | ConfigStatus::Missing => status_str
+                .if_supports_color(Stdout, |t| t.dimmed())
+                .to_string(),
+            _ => status_str
+                .if_supports_color(Stdout, |t| t.red())
+                .to_string(),
+        };
+        builder.push_record([(i + 1).to_string(), path.into(), status_str]);
     }
 
     let mut table = builder.build();
     println!("{}", table.with(Style::sharp()));
 }
 
-/// Print login state table
+/// Print login state table, with the mac address column only if the portal reports `user_mac`
 ///
 /// # Example output
 ///
-/// ┌────────────────┬───────────────┬───────────────┬─────────┐
-/// │ Traffic Used   │ Online Time   │ User Balance  │ Wallet  │
-/// ├────────────────┼───────────────┼───────────────┼─────────┤
-/// │ 188.10 GiB     │ 2 months      │ 10.00         │ 0.00    │
-/// └────────────────┴───────────────┴───────────────┴─────────┘
+/// ┌────────────────┬───────────────┬───────────────┬─────────┬───────────────────┐
+/// │ Traffic Used   │ Online Time   │ User Balance  │ Wallet  │ MAC Address       │
+/// ├────────────────┼───────────────┼───────────────┼─────────┼───────────────────┤
+/// │ 188.10 GiB     │ 2 months      │ 10.00         │ 0.00    │ 00:1a:2b:3c:4d:5e │
+/// └────────────────┴───────────────┴───────────────┴─────────┴───────────────────┘
 pub fn print_login_state(state: SrunLoginState) {
     let mut builder = Builder::default();
-    builder.set_header(["Traffic Used", "Online Time", "User Balance", "Wallet"]);
+    let mut header = vec!["Traffic Used", "Online Time", "User Balance", "Wallet"];
+    if state.user_mac.is_some() {
+        header.push("MAC Address");
+    }
+    builder.set_header(header);
 
     // parse outputs from login state response
     let traffic_used = state.sum_bytes.unwrap_or(0);
@@ -61,7 +82,7 @@ pub fn print_login_state(state: SrunLoginState) {
     let user_balance = state.user_balance.unwrap_or(0.0);
     let wallet = state.wallet_balance.unwrap_or(0.0);
 
-    builder.push_record([
+    let mut record = vec![
         format_size(traffic_used, BINARY)
             .if_supports_color(Stdout, |t| t.green())
             .to_string(),
@@ -75,8 +96,63 @@ pub fn print_login_state(state: SrunLoginState) {
         format!("{:.2}", wallet)
             .if_supports_color(Stdout, |t| t.magenta())
             .to_string(),
-    ]);
+    ];
+    if let Some(user_mac) = state.user_mac {
+        record.push(user_mac);
+    }
+    builder.push_record(record);
 
     let mut table = builder.build();
     println!("{}", table.with(Style::sharp()).with(Width::increase(60)));
 }
+
+/// Print login states of all managed hosts in router mode
+///
+/// # Example output
+///
+/// ┌─────────────┬────────────┬─────────┬──────────────┬─────────────┐
+/// │ IP          │ Account    │ Status  │ Traffic Used │ Online Time │
+/// ├─────────────┼────────────┼─────────┼──────────────┼─────────────┤
+/// │ 10.62.0.101 │ 1120200000 │ online  │ 12.40 GiB    │ 3 days      │
+/// │ 10.62.0.102 │ 1120200001 │ offline │ -            │ -           │
+/// └─────────────┴────────────┴─────────┴──────────────┴─────────────┘
+pub fn print_managed_states(states: &[(&ManagedHost, Result<SrunLoginState>)]) {
+    let mut builder = Builder::default();
+    builder.set_header(["IP", "Account", "Status", "Traffic Used", "Online Time"]);
+
+    for (host, state) in states {
+        let (status, traffic_used, online_time) = match state {
+            Ok(state) if state.error == "ok" => (
+                "online"
+                    .if_supports_color(Stdout, |t| t.green())
+                    .to_string(),
+                format_size(state.sum_bytes.unwrap_or(0), BINARY),
+                HumanTime::from(Duration::seconds(state.sum_seconds.unwrap_or(0)))
+                    .to_text_en(Rough, Present),
+            ),
+            Ok(_) => (
+                "offline"
+                    .if_supports_color(Stdout, |t| t.blue())
+                    .to_string(),
+                "-".into(),
+                "-".into(),
+            ),
+            Err(_) => (
+                "unknown".if_supports_color(Stdout, |t| t.red()).to_string(),
+                "-".into(),
+                "-".into(),
+            ),
+        };
+
+        builder.push_record([
+            host.ip.to_string(),
+            host.username.clone(),
+            status,
+            traffic_used,
+            online_time,
+        ]);
+    }
+
+    let mut table = builder.build();
+    println!("{}", table.with(Style::sharp()));
+}
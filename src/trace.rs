@@ -0,0 +1,678 @@
+use crate::transport::HttpResponse;
+use crate::transport::HttpTransport;
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use url::form_urlencoded::byte_serialize;
+use url::Url;
+
+/// Query parameters and response fields that are always redacted, along with any `*token` (e.g.,
+/// `access_token` in login responses)
+const REDACTED: &[&str] = &["password", "info", "chksum", "sign", "challenge", "token"];
+
+/// Query parameters and response fields that are replaced with a placeholder when anonymizing
+const ANONYMIZED: &[(&str, &str)] = &[
+    ("username", "<username>"),
+    ("user_name", "<username>"),
+    ("real_name", "<real_name>"),
+    ("user_mac", "<mac>"),
+    ("ip", "<ip>"),
+    ("ipv6", "<ip6>"),
+    ("online_ip", "<ip>"),
+    ("online_ip6", "<ip6>"),
+    ("client_ip", "<ip>"),
+];
+
+/// Request and response headers that are always redacted, as they carry credentials
+const REDACTED_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// Values shorter than this are not replaced in free text (e.g., `::` or `0`)
+const MIN_REPLACED_LEN: usize = 4;
+
+/// A HAR-like capture of portal traffic
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Trace {
+    pub log: TraceLog,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TraceLog {
+    pub creator: String,
+    pub entries: Vec<TraceEntry>,
+}
+
+/// A single request and its response, or the error if the request failed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEntry {
+    pub started_date_time: String,
+    /// Time taken by the request, in milliseconds
+    pub time: f64,
+    pub request: TraceRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<TraceResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceRequest {
+    pub method: String,
+    /// Full URL including the query string
+    pub url: String,
+    pub query_string: Vec<NameValue>,
+    /// Headers sent with the request, empty if unknown (e.g., the request failed)
+    #[serde(default)]
+    pub headers: Vec<NameValue>,
+    pub follow_redirects: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceResponse {
+    /// Final URL of the response, after redirects if followed
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<NameValue>,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+impl From<TraceResponse> for HttpResponse {
+    fn from(resp: TraceResponse) -> Self {
+        HttpResponse {
+            url: resp.url,
+            status: resp.status,
+            headers: resp
+                .headers
+                .into_iter()
+                .map(|h| (h.name, h.value))
+                .collect(),
+            body: resp.content,
+            request_headers: vec![],
+        }
+    }
+}
+
+/// Redacts secrets, and optionally usernames and IP addresses, from traced traffic
+///
+/// Sensitive values found in query parameters and JSON(P) responses are remembered, so that they
+/// are also replaced wherever they show up later (e.g., in redirect URLs or HTML pages).
+#[derive(Debug, Default)]
+struct Redactor {
+    anonymize: bool,
+    replacements: Vec<(String, String)>,
+}
+
+impl Redactor {
+    fn placeholder(&self, key: &str) -> Option<&'static str> {
+        if REDACTED.contains(&key) || key.ends_with("token") {
+            return Some("[redacted]");
+        }
+        match self.anonymize {
+            true => ANONYMIZED
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, placeholder)| *placeholder),
+            false => None,
+        }
+    }
+
+    fn learn(&mut self, key: &str, value: &str) {
+        if let Some(placeholder) = self.placeholder(key) {
+            if !value.is_empty() && !self.replacements.iter().any(|(v, _)| v == value) {
+                self.replacements
+                    .push((value.to_string(), placeholder.to_string()));
+            }
+        }
+    }
+
+    fn learn_json(&mut self, value: &Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    match value {
+                        Value::String(s) => self.learn(key, s),
+                        Value::Number(n) => self.learn(key, &n.to_string()),
+                        _ => self.learn_json(value),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|v| self.learn_json(v)),
+            _ => {}
+        }
+    }
+
+    /// Learn sensitive values from a JSON or JSONP (`jsonp({...})`) body
+    fn learn_body(&mut self, body: &str) {
+        let json = match (body.find('('), body.rfind(')')) {
+            (Some(start), Some(end)) if start < end => &body[start + 1..end],
+            _ => body,
+        };
+        if let Ok(value) = serde_json::from_str::<Value>(json) {
+            self.learn_json(&value);
+        }
+    }
+
+    fn redact(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (value, placeholder) in &self.replacements {
+            if value.len() >= MIN_REPLACED_LEN {
+                text = text.replace(value.as_str(), placeholder);
+            }
+        }
+        text
+    }
+
+    fn redact_headers(&self, headers: &[(String, String)]) -> Vec<NameValue> {
+        headers
+            .iter()
+            .map(|(name, value)| NameValue {
+                name: name.clone(),
+                value: match REDACTED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                    true => "[redacted]".into(),
+                    false => self.redact(value),
+                },
+            })
+            .collect()
+    }
+
+    fn redact_query(&self, query: &[(&str, &str)]) -> Vec<NameValue> {
+        query
+            .iter()
+            .map(|(name, value)| NameValue {
+                name: name.to_string(),
+                value: match self.placeholder(name) {
+                    Some(placeholder) => placeholder.to_string(),
+                    None => self.redact(value),
+                },
+            })
+            .collect()
+    }
+}
+
+/// A transport that records every request and response to a HAR-like JSON file
+///
+/// Passwords, encrypted payloads (`info`), checksums, tokens and credential headers (e.g.,
+/// `Cookie`) are always redacted. Usernames and IP addresses are replaced with placeholders if
+/// `anonymize` is set. The file is rewritten after each request, so it is complete even if a later
+/// step fails.
+#[derive(Debug)]
+pub struct TracingTransport<T> {
+    inner: T,
+    path: PathBuf,
+    redactor: Mutex<Redactor>,
+    trace: Mutex<Trace>,
+}
+
+impl<T> TracingTransport<T> {
+    pub fn new(inner: T, path: impl Into<PathBuf>, anonymize: bool) -> Self {
+        Self {
+            inner,
+            path: path.into(),
+            redactor: Mutex::new(Redactor {
+                anonymize,
+                ..Default::default()
+            }),
+            trace: Mutex::new(Trace {
+                log: TraceLog {
+                    creator: format!("bitsrun {}", env!("CARGO_PKG_VERSION")),
+                    entries: vec![],
+                },
+            }),
+        }
+    }
+
+    fn record(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+        follow_redirects: bool,
+        started: (String, Instant),
+        result: &Result<HttpResponse>,
+    ) -> Result<()> {
+        let mut redactor = self.redactor.lock().unwrap();
+        query
+            .iter()
+            .for_each(|(key, value)| redactor.learn(key, value));
+        if let Ok(resp) = result {
+            redactor.learn_body(&resp.body);
+        }
+
+        let query_string = redactor.redact_query(query);
+        let url = redactor.redact(url);
+        let full_url =
+            Url::parse_with_params(&url, query_string.iter().map(|q| (&q.name, &q.value)))
+                .map(|u| u.to_string())
+                .unwrap_or(url);
+        let entry = TraceEntry {
+            started_date_time: started.0,
+            time: started.1.elapsed().as_secs_f64() * 1000.0,
+            request: TraceRequest {
+                method: "GET".into(),
+                url: full_url,
+                query_string,
+                headers: result
+                    .as_ref()
+                    .map(|resp| redactor.redact_headers(&resp.request_headers))
+                    .unwrap_or_default(),
+                follow_redirects,
+            },
+            response: result.as_ref().ok().map(|resp| TraceResponse {
+                url: redactor.redact(&resp.url),
+                status: resp.status,
+                headers: redactor.redact_headers(&resp.headers),
+                content: redactor.redact(&resp.body),
+            }),
+            error: result
+                .as_ref()
+                .err()
+                .map(|e| redactor.redact(&format!("{:#}", e))),
+        };
+
+        let mut trace = self.trace.lock().unwrap();
+        trace.log.entries.push(entry);
+        let raw_json = serde_json::to_string_pretty(&*trace)?;
+        write_private(&self.path, raw_json.as_bytes())
+            .with_context(|| format!("failed to write trace file `{}`", self.path.display()))
+    }
+}
+
+/// Write a file only readable and writable by the owner (0600), even if it already exists
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)
+}
+
+impl<T: HttpTransport + Sync> HttpTransport for TracingTransport<T> {
+    async fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        let started = (Utc::now().to_rfc3339(), Instant::now());
+        let result = self.inner.get(url, query).await;
+        self.record(url, query, true, started, &result)?;
+        result
+    }
+
+    async fn get_no_redirect(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        let started = (Utc::now().to_rfc3339(), Instant::now());
+        let result = self.inner.get_no_redirect(url, query).await;
+        self.record(url, query, false, started, &result)?;
+        result
+    }
+}
+
+/// A dummy token of the usual length, replayed in place of redacted values (e.g., the challenge)
+const REPLAYED_TOKEN: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Dummy values replayed in place of anonymized ones, which must still parse (e.g., as `IpAddr`)
+const REPLAYED: &[(&str, &str)] = &[
+    ("[redacted]", REPLAYED_TOKEN),
+    ("<username>", "anonymous"),
+    ("<real_name>", "anonymous"),
+    ("<mac>", "00:00:00:00:00:00"),
+    ("<ip>", "192.0.2.1"),
+    ("<ip6>", "2001:db8::1"),
+];
+
+/// Replace redacted and anonymized placeholders, as is or percent-encoded (e.g., in URLs)
+fn replay_placeholders(text: &str) -> String {
+    REPLAYED
+        .iter()
+        .fold(text.to_string(), |text, (placeholder, value)| {
+            let encoded: String = byte_serialize(placeholder.as_bytes()).collect();
+            text.replace(placeholder, value).replace(&encoded, value)
+        })
+}
+
+/// A transport that serves responses from a trace file recorded with `TracingTransport`
+///
+/// Each request is served the first unused entry recorded for the same URL (ignoring the query
+/// string), so a captured session can be reproduced offline even if it is replayed with slightly
+/// different options. Redacted values are replayed as a dummy token, and anonymized ones as dummy
+/// values of the same kind (e.g., `<ip>` as `192.0.2.1`).
+#[derive(Debug)]
+pub struct ReplayTransport {
+    entries: Mutex<Vec<Option<TraceEntry>>>,
+}
+
+impl ReplayTransport {
+    /// Load a trace file
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let raw_json = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read trace file `{}`", path.display()))?;
+        Self::from_json(&raw_json)
+            .with_context(|| format!("failed to parse trace file `{}`", path.display()))
+    }
+
+    /// Parse a trace from its JSON content
+    pub fn from_json(raw_json: &str) -> Result<Self> {
+        let trace = serde_json::from_str::<Trace>(raw_json)?;
+        Ok(Self {
+            entries: Mutex::new(trace.log.entries.into_iter().map(Some).collect()),
+        })
+    }
+
+    fn serve(&self, url: &str) -> Result<HttpResponse> {
+        let base = |url: &str| url.split('?').next().unwrap_or_default().to_string();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .iter_mut()
+            .find(|e| matches!(e, Some(e) if base(&e.request.url) == base(url)))
+            .and_then(Option::take)
+            .ok_or_else(|| anyhow!("no recorded response left for `{}`", url))?;
+        match (entry.response, entry.error) {
+            (Some(resp), _) => {
+                let resp = HttpResponse::from(resp);
+                Ok(HttpResponse {
+                    url: replay_placeholders(&resp.url),
+                    status: resp.status,
+                    headers: resp
+                        .headers
+                        .iter()
+                        .map(|(name, value)| (name.clone(), replay_placeholders(value)))
+                        .collect(),
+                    body: replay_placeholders(&resp.body),
+                    request_headers: vec![],
+                })
+            }
+            (None, error) => bail!(
+                "recorded request failed, {}",
+                error.unwrap_or_else(|| "without a response".into())
+            ),
+        }
+    }
+}
+
+impl HttpTransport for ReplayTransport {
+    async fn get(&self, url: &str, _query: &[(&str, &str)]) -> Result<HttpResponse> {
+        self.serve(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::SrunClient;
+    use crate::client::SrunPortalResponse;
+    use crate::scheme::SrunScheme;
+    use crate::transport::ScriptedTransport;
+
+    use std::net::IpAddr;
+
+    fn redactor(anonymize: bool) -> Redactor {
+        Redactor {
+            anonymize,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn placeholder_redacts_secrets_and_tokens() {
+        let redactor = redactor(false);
+        for key in [
+            "password",
+            "info",
+            "chksum",
+            "challenge",
+            "token",
+            "access_token",
+        ] {
+            assert_eq!(redactor.placeholder(key), Some("[redacted]"), "{}", key);
+        }
+        assert_eq!(redactor.placeholder("username"), None);
+        assert_eq!(redactor.placeholder("callback"), None);
+    }
+
+    #[test]
+    fn placeholder_anonymizes_only_if_asked() {
+        assert_eq!(redactor(true).placeholder("username"), Some("<username>"));
+        assert_eq!(redactor(true).placeholder("online_ip"), Some("<ip>"));
+        assert_eq!(redactor(false).placeholder("online_ip"), None);
+    }
+
+    #[test]
+    fn learn_body_parses_jsonp() {
+        let mut redactor = redactor(true);
+        redactor.learn_body(
+            r#"jsonp({"access_token":"a1b2c3d4e5","online_ip":"10.62.1.2","nested":{"user_name":"1120201234"},"st":1})"#,
+        );
+        assert_eq!(
+            redactor.redact("token=a1b2c3d4e5&ip=10.62.1.2&user=1120201234"),
+            "token=[redacted]&ip=<ip>&user=<username>"
+        );
+    }
+
+    #[test]
+    fn learn_body_ignores_non_json() {
+        let mut redactor = redactor(true);
+        redactor.learn_body("<html>password: hunter2</html>");
+        assert!(redactor.replacements.is_empty());
+    }
+
+    #[test]
+    fn redact_query_replaces_values_by_key() {
+        let mut redactor = redactor(false);
+        let query = [
+            ("callback", "jsonp"),
+            ("username", "1120201234"),
+            ("password", "{MD5}0123456789abcdef"),
+            (
+                "redirect",
+                "http://10.0.0.55/?password={MD5}0123456789abcdef",
+            ),
+        ];
+        query.iter().for_each(|(k, v)| redactor.learn(k, v));
+        let redacted: Vec<(String, String)> = redactor
+            .redact_query(&query)
+            .into_iter()
+            .map(|q| (q.name, q.value))
+            .collect();
+        assert_eq!(
+            redacted,
+            [
+                ("callback", "jsonp"),
+                ("username", "1120201234"),
+                ("password", "[redacted]"),
+                ("redirect", "http://10.0.0.55/?password=[redacted]"),
+            ]
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+    }
+
+    #[test]
+    fn learned_values_are_replaced_in_free_text() {
+        let mut redactor = redactor(true);
+        redactor.learn("username", "1120201234");
+        redactor.learn("ip", "10.62.1.2");
+        redactor.learn("ipv6", "::");
+        assert_eq!(
+            redactor.redact("<p>Welcome 1120201234 (10.62.1.2, ::)</p>"),
+            "<p>Welcome <username> (<ip>, ::)</p>"
+        );
+    }
+
+    #[tokio::test]
+    async fn tracing_transport_records_redacted_headers() {
+        let path =
+            std::env::temp_dir().join(format!("bitsrun-headers-{}.json", std::process::id()));
+        let headers = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        let inner = ScriptedTransport::new().respond_with(HttpResponse {
+            url: "http://10.0.0.55/srun_portal_pc?ac_id=1".into(),
+            status: 200,
+            headers: headers(&[("set-cookie", "PHPSESSID=s3cr3t"), ("server", "nginx")]),
+            body: "<html></html>".into(),
+            request_headers: headers(&[
+                ("accept", "*/*"),
+                ("user-agent", "bitsrun"),
+                ("Cookie", "PHPSESSID=s3cr3t"),
+                ("authorization", "Basic aHVudGVyMg=="),
+            ]),
+        });
+        let client = TracingTransport::new(inner, &path, false);
+        client.get("http://10.0.0.55", &[]).await.unwrap();
+
+        let raw_json = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let trace: Trace = serde_json::from_str(&raw_json).unwrap();
+        let pairs = |headers: &[NameValue]| -> Vec<(String, String)> {
+            headers
+                .iter()
+                .map(|h| (h.name.clone(), h.value.clone()))
+                .collect()
+        };
+        let entry = &trace.log.entries[0];
+        assert_eq!(
+            pairs(&entry.request.headers),
+            headers(&[
+                ("accept", "*/*"),
+                ("user-agent", "bitsrun"),
+                ("Cookie", "[redacted]"),
+                ("authorization", "[redacted]"),
+            ])
+        );
+        assert_eq!(
+            pairs(&entry.response.as_ref().unwrap().headers),
+            headers(&[("set-cookie", "[redacted]"), ("server", "nginx")])
+        );
+        assert!(!raw_json.contains("s3cr3t"));
+    }
+
+    #[tokio::test]
+    async fn tracing_transport_writes_a_private_redacted_file() {
+        let path = std::env::temp_dir().join(format!("bitsrun-trace-{}.json", std::process::id()));
+        let inner = ScriptedTransport::new().respond(
+            "http://10.0.0.55/cgi-bin/srun_portal",
+            r#"jsonp({"access_token":"a1b2c3d4e5f6","error":"ok"})"#,
+        );
+        let client = TracingTransport::new(inner, &path, false);
+        client
+            .get(
+                "http://10.0.0.55/cgi-bin/srun_portal",
+                &[("password", "{MD5}0123456789abcdef")],
+            )
+            .await
+            .unwrap();
+
+        let raw_json = fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(!raw_json.contains("a1b2c3d4e5f6"));
+        assert!(!raw_json.contains("0123456789abcdef"));
+        assert!(raw_json.contains(r#""access_token\":\"[redacted]\""#));
+    }
+
+    async fn login_with<T: HttpTransport>(http_client: T) -> Result<SrunPortalResponse> {
+        SrunClient::builder()
+            .username("1120201234")
+            .password("hunter2")
+            .ac_id("1".to_string())
+            .scheme(SrunScheme::default())
+            .build_with(http_client)
+            .login(false, false)
+            .await
+    }
+
+    #[tokio::test]
+    async fn anonymized_trace_replays() {
+        let path = std::env::temp_dir().join(format!("bitsrun-anon-{}.json", std::process::id()));
+        let inner = ScriptedTransport::new()
+            .respond(
+                "http://10.0.0.55/cgi-bin/rad_user_info",
+                r#"jsonp({"error":"not_online_error","online_ip":"10.62.1.2","client_ip":"10.62.1.2"})"#,
+            )
+            .respond(
+                "http://10.0.0.55/cgi-bin/get_challenge",
+                r#"jsonp({"challenge":"8f1c2d3e4b5a6978","client_ip":"10.62.1.2"})"#,
+            )
+            .respond(
+                "http://10.0.0.55/cgi-bin/srun_portal",
+                r#"jsonp({"client_ip":"10.62.1.2","online_ip":"10.62.1.2","error":"ok","error_msg":"","res":"ok","username":"1120201234"})"#,
+            );
+        login_with(TracingTransport::new(inner, &path, true))
+            .await
+            .unwrap();
+        let trace = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(trace.contains("<ip>") && !trace.contains("10.62.1.2"));
+
+        // a trace attached to a bug report reproduces the session with dummy values
+        let resp = login_with(ReplayTransport::from_json(&trace).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.error, "ok");
+        assert_eq!(resp.online_ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+        assert_eq!(resp.username.as_deref(), Some("anonymous"));
+    }
+
+    #[tokio::test]
+    async fn replayed_trace_logs_in_and_out() {
+        let trace = include_str!("../tests/fixtures/trace-login-logout.json");
+        let mut client = SrunClient::builder()
+            .username("1120201234")
+            .password("hunter2")
+            .ip("10.62.1.2".parse::<IpAddr>().unwrap())
+            .ac_id("1".to_string())
+            .scheme(SrunScheme::default())
+            .build_with(ReplayTransport::from_json(trace).unwrap());
+
+        let resp = client.login(false, false).await.unwrap();
+        assert_eq!(resp.error, "ok");
+        assert_eq!(resp.suc_msg.as_deref(), Some("login_ok"));
+
+        // the login state is fetched again by a later `bitsrun logout`
+        client.login_state = None;
+        let resp = client.logout(false, false).await.unwrap();
+        assert_eq!(resp.error, "ok");
+        // every recorded response has been served
+        assert!(client.logout(true, false).await.is_err());
+    }
+}
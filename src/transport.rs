@@ -0,0 +1,251 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use reqwest::header::HeaderMap;
+use reqwest::header::HeaderValue;
+use reqwest::header::ACCEPT;
+use reqwest::header::USER_AGENT;
+use reqwest::redirect::Policy;
+use reqwest::Client;
+use reqwest::ClientBuilder;
+use reqwest::Request;
+use reqwest::Response;
+use url::Url;
+
+/// A response from the portal
+#[derive(Debug, Clone, Default)]
+pub struct HttpResponse {
+    /// The final URL of the response, after redirects if followed
+    pub url: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// Headers sent with the request, if known to the transport (`Host` is always left out)
+    pub request_headers: Vec<(String, String)>,
+}
+
+impl HttpResponse {
+    /// Get the value of a header by its case-insensitive name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// HTTP transport used to talk to the SRUN portal
+///
+/// `ReqwestTransport` is the default implementation. Others can be plugged into `SrunClient` to
+/// reuse the protocol logic with another HTTP stack, or to test it without a socket.
+pub trait HttpTransport {
+    /// Send a GET request with query parameters, returning the final URL and response body
+    fn get(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+    ) -> impl Future<Output = Result<HttpResponse>> + Send;
+
+    /// Send a GET request without following redirects, e.g., to read the `Location` header
+    ///
+    /// Transports that are unable to do so follow redirects as `get` does.
+    fn get_no_redirect(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        self.get(url, query)
+    }
+}
+
+/// Share a transport between clients, e.g., several hosts recorded into the same trace
+impl<T: HttpTransport + Sync> HttpTransport for &T {
+    fn get(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        (**self).get(url, query)
+    }
+
+    fn get_no_redirect(
+        &self,
+        url: &str,
+        query: &[(&str, &str)],
+    ) -> impl Future<Output = Result<HttpResponse>> + Send {
+        (**self).get_no_redirect(url, query)
+    }
+}
+
+pub(crate) fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(key, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (key.to_string(), value)
+        })
+        .collect()
+}
+
+async fn into_response(resp: Response, request: &Request) -> Result<HttpResponse> {
+    let url = resp.url().to_string();
+    let status = resp.status().as_u16();
+    let headers = header_pairs(resp.headers());
+    let body = resp.text().await?;
+    Ok(HttpResponse {
+        url,
+        status,
+        headers,
+        body,
+        request_headers: header_pairs(request.headers()),
+    })
+}
+
+/// Send a request, keeping track of its headers
+///
+/// Default headers of the client are only added on sending, so they are unknown here unless set
+/// on the request itself.
+async fn send(client: &Client, request: Request) -> Result<HttpResponse> {
+    let sent = request
+        .try_clone()
+        .expect("GET requests have no streaming body");
+    into_response(client.execute(request).await?, &sent).await
+}
+
+impl HttpTransport for Client {
+    async fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        send(self, Client::get(self, url).query(query).build()?).await
+    }
+}
+
+/// The default transport, a `reqwest` client and a sibling client that never follows redirects
+///
+/// Its headers are set on every request instead of the clients, so that they can be traced.
+#[derive(Debug, Clone)]
+pub struct ReqwestTransport {
+    client: Client,
+    no_redirect: Client,
+    headers: HeaderMap,
+}
+
+impl ReqwestTransport {
+    /// Build both clients with the same settings (e.g., local address and timeout)
+    pub fn new(configure: impl Fn(ClientBuilder) -> ClientBuilder) -> Result<ReqwestTransport> {
+        let client = configure(Client::builder())
+            .build()
+            .with_context(|| "failed to build http client")?;
+        let no_redirect = configure(Client::builder())
+            .redirect(Policy::none())
+            .build()
+            .with_context(|| "failed to build http client")?;
+        Ok(ReqwestTransport {
+            client,
+            no_redirect,
+            headers: default_headers(),
+        })
+    }
+
+    /// Send a `User-Agent` header with every request
+    pub fn user_agent(mut self, user_agent: &str) -> Result<ReqwestTransport> {
+        let user_agent = HeaderValue::from_str(user_agent)
+            .with_context(|| format!("invalid user agent `{}`", user_agent))?;
+        self.headers.insert(USER_AGENT, user_agent);
+        Ok(self)
+    }
+}
+
+/// Headers sent by `reqwest` clients by default
+fn default_headers() -> HeaderMap {
+    HeaderMap::from_iter([(ACCEPT, HeaderValue::from_static("*/*"))])
+}
+
+impl Default for ReqwestTransport {
+    fn default() -> Self {
+        Self::from(Client::new())
+    }
+}
+
+/// Reuse an existing client, note that its settings can't be copied to the no-redirect client
+impl From<Client> for ReqwestTransport {
+    fn from(client: Client) -> Self {
+        let no_redirect = Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .unwrap_or_default();
+        ReqwestTransport {
+            client,
+            no_redirect,
+            headers: default_headers(),
+        }
+    }
+}
+
+impl HttpTransport for ReqwestTransport {
+    async fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        let request = self.client.get(url).query(query);
+        send(&self.client, request.headers(self.headers.clone()).build()?).await
+    }
+
+    async fn get_no_redirect(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        let request = self.no_redirect.get(url).query(query);
+        send(
+            &self.no_redirect,
+            request.headers(self.headers.clone()).build()?,
+        )
+        .await
+    }
+}
+
+/// A scripted in-memory transport, which serves queued responses in order (for tests)
+///
+/// All requests are recorded as full URLs including the query string.
+#[derive(Debug, Default)]
+pub struct ScriptedTransport {
+    responses: Mutex<VecDeque<HttpResponse>>,
+    requests: Mutex<Vec<String>>,
+}
+
+impl ScriptedTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a `200 OK` response, where `url` is the final URL after redirects
+    pub fn respond(self, url: &str, body: &str) -> Self {
+        self.respond_with(HttpResponse {
+            url: url.into(),
+            status: 200,
+            headers: vec![],
+            body: body.into(),
+            request_headers: vec![],
+        })
+    }
+
+    /// Queue a response with full control over its status and headers
+    pub fn respond_with(self, response: HttpResponse) -> Self {
+        self.responses.lock().unwrap().push_back(response);
+        self
+    }
+
+    /// Requests sent so far, as full URLs including the query string
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl HttpTransport for ScriptedTransport {
+    async fn get(&self, url: &str, query: &[(&str, &str)]) -> Result<HttpResponse> {
+        let request = Url::parse_with_params(url, query)
+            .with_context(|| format!("failed to parse url `{}`", url))?;
+        self.requests.lock().unwrap().push(request.to_string());
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| anyhow!("no scripted response left for `{}`", request))
+    }
+}
@@ -1,6 +1,10 @@
 use crate::config;
+use crate::scheme::SrunScheme;
+use crate::secret::Secret;
 
+use std::fmt;
 use std::fs;
+use std::net::IpAddr;
 
 use anyhow::Context;
 use anyhow::Result;
@@ -8,43 +12,67 @@ use owo_colors::OwoColorize;
 use owo_colors::Stream::Stdout;
 use serde::Deserialize;
 use serde::Serialize;
+use zeroize::Zeroizing;
 
 /// Campus network user credentials that are finalized
 #[derive(Debug, Default)]
 pub struct BitUser {
     pub username: String,
-    pub password: String,
+    pub password: Secret,
     pub dm: bool,
+    pub double_stack: bool,
+    pub interface: Option<String>,
+    pub source_ip: Option<IpAddr>,
+    pub os: Option<String>,
+    pub name: Option<String>,
+    pub ac_id: Option<String>,
+    pub scheme: Option<SrunScheme>,
 }
 
 /// Partial campus network user credentials
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct BitUserPartial {
     pub username: Option<String>,
-    pub password: Option<String>,
+    pub password: Option<Secret>,
     pub dm: Option<bool>,
+    pub double_stack: Option<bool>,
+    pub interface: Option<String>,
+    pub source_ip: Option<IpAddr>,
+    pub os: Option<String>,
+    pub name: Option<String>,
+    pub ac_id: Option<String>,
+    pub scheme: Option<SrunScheme>,
 }
 
 impl BitUserPartial {
-    pub fn new(username: &Option<String>, password: &Option<String>, dm: Option<bool>) -> Self {
+    pub fn new(
+        username: &Option<String>,
+        password: &Option<String>,
+        dm: Option<bool>,
+        double_stack: Option<bool>,
+    ) -> Self {
         Self {
             username: username.clone(),
-            password: password.clone(),
+            password: password.as_deref().map(Secret::from),
             dm,
+            double_stack,
+            ..Default::default()
         }
     }
 }
 
 /// Parse bit user credentials from config file
-fn parse_bit_user_config(config_path: &Option<String>) -> Result<BitUserPartial> {
+pub fn parse_bit_user_config(config_path: &Option<String>) -> Result<BitUserPartial> {
     let config = config::validate_config_file(config_path)?;
 
-    let user_str_from_file = fs::read_to_string(&config).with_context(|| {
-        format!(
-            "failed to read config file `{}`",
-            &config.if_supports_color(Stdout, |t| t.underline())
-        )
-    })?;
+    let user_str_from_file = fs::read_to_string(&config)
+        .map(Zeroizing::new)
+        .with_context(|| {
+            format!(
+                "failed to read config file `{}`",
+                &config.if_supports_color(Stdout, |t| t.underline())
+            )
+        })?;
     let user_from_file =
         serde_json::from_str::<BitUserPartial>(&user_str_from_file).with_context(|| {
             format!(
@@ -55,70 +83,131 @@ fn parse_bit_user_config(config_path: &Option<String>) -> Result<BitUserPartial>
     Ok(user_from_file)
 }
 
+/// Credentials that are found neither on the command line nor in the config file, and can't be
+/// prompted for
+#[derive(Debug)]
+pub struct MissingCredentials(pub &'static str);
+
+impl fmt::Display for MissingCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no {0} found in the command line arguments or config file, and prompting is disabled \
+            (`--no-input` or no TTY), specify the {0} with `--{0}` or in the config file",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for MissingCredentials {}
+
 /// Get campus network user credentials from command line arguments or config file
 ///
 /// Note that when logging out, `password` is not required.
 /// In this case, `require_password` should be set to `false`.
+/// Registered dumb terminals (`dm`) are authenticated with their mac address and never require a
+/// password either.
+///
+/// Missing credentials are prompted for if `interactive`, or fail with `MissingCredentials`.
 pub fn finalize_bit_user(
     username: &Option<String>,
     password: &Option<String>,
     dm: bool,
+    double_stack: bool,
     config_path: &Option<String>,
     require_password: bool,
+    interactive: bool,
 ) -> Result<BitUser> {
-    let mut bit_user = BitUserPartial::new(username, password, Some(dm));
-
-    // username and password priority: command line > config file > prompt
-    if bit_user.username.is_none() | (require_password & bit_user.password.is_none()) {
-        let mut user_from_file = BitUserPartial::default();
-        match parse_bit_user_config(config_path) {
-            Ok(value) => user_from_file = value,
-            Err(e) => println!(
-                "{} {}",
-                "warning:".if_supports_color(Stdout, |t| t.yellow()),
-                e
-            ),
-        }
+    let mut bit_user = BitUserPartial::new(username, password, Some(dm), Some(double_stack));
+    let require_password = require_password & !dm;
+    let missing_credentials =
+        bit_user.username.is_none() || (require_password && bit_user.password.is_none());
 
-        if user_from_file.dm.is_none() & !dm {
-            println!(
-                "{} logout endpoint not specified in config file! \
-                logging out may encounter unexpected results",
-                "warning:".if_supports_color(Stdout, |t| t.yellow()),
-            );
-            println!(
-                "{} if this device is a '{}', explicity specify `{}` to use alternative logout endpoint",
-                "warning:".if_supports_color(Stdout, |t| t.yellow()),
-                "registered dumb terminal".if_supports_color(Stdout, |t| t.on_yellow()),
-                "--dm".if_supports_color(Stdout, |t| t.underline())
-            );
+    // the config file is read if it exists, but only required for credentials missing otherwise
+    let user_from_file = match parse_bit_user_config(config_path) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            if missing_credentials || config_path.is_some() {
+                println!(
+                    "{} {}",
+                    "warning:".if_supports_color(Stdout, |t| t.yellow()),
+                    e
+                );
+            }
+            None
         }
+    };
 
-        match user_from_file.username {
-            Some(username) => bit_user.username.get_or_insert(username),
-            None => bit_user.username.get_or_insert_with(|| {
+    // warn about the dm endpoint if the config file was consulted without specifying it
+    let consulted = missing_credentials || user_from_file.is_some();
+    let dm_unset = user_from_file.as_ref().and_then(|user| user.dm).is_none();
+    if consulted && dm_unset && !dm {
+        println!(
+            "{} dm endpoint not specified in config file! \
+            logging in or out may encounter unexpected results",
+            "warning:".if_supports_color(Stdout, |t| t.yellow()),
+        );
+        println!(
+            "{} if this device is a '{}', explicity specify `{}` to use alternative dm endpoints",
+            "warning:".if_supports_color(Stdout, |t| t.yellow()),
+            "registered dumb terminal".if_supports_color(Stdout, |t| t.on_yellow()),
+            "--dm".if_supports_color(Stdout, |t| t.underline())
+        );
+    }
+    let user_from_file = user_from_file.unwrap_or_default();
+
+    // dumb terminal mode is enabled if either the command line or config file asks for it
+    if user_from_file.dm.unwrap_or_default() {
+        bit_user.dm = Some(true);
+    }
+    let require_password = require_password & !bit_user.dm.unwrap_or_default();
+
+    // dual-stack login is enabled if either the command line or config file asks for it
+    if user_from_file.double_stack.unwrap_or_default() {
+        bit_user.double_stack = Some(true);
+    }
+
+    // network binding from the config file, command line arguments take priority later on
+    bit_user.interface = user_from_file.interface;
+    bit_user.source_ip = user_from_file.source_ip;
+
+    // device identity reported to the portal
+    bit_user.os = user_from_file.os;
+    bit_user.name = user_from_file.name;
+
+    // ac_id of the current network, discovered if not specified
+    bit_user.ac_id = user_from_file.ac_id;
+
+    // login scheme of the portal, probed if not specified
+    bit_user.scheme = user_from_file.scheme;
+
+    // username and password priority: command line > config file > prompt, unless disabled
+    if bit_user.username.is_none() {
+        bit_user.username = match user_from_file.username {
+            Some(username) => Some(username),
+            None if !interactive => return Err(MissingCredentials("username").into()),
+            None => Some(
                 rprompt::prompt_reply(
                     "-> please enter your campus id: ".if_supports_color(Stdout, |t| t.dimmed()),
                 )
-                .with_context(|| "failed to read username")
-                .unwrap()
-            }),
+                .with_context(|| "failed to read username")?,
+            ),
         };
+    }
 
-        match user_from_file.password {
-            Some(password) => bit_user.password.get_or_insert(password),
-            None => bit_user.password.get_or_insert_with(|| {
-                if require_password {
-                    rpassword::prompt_password(
-                        "-> please enter your password: ".if_supports_color(Stdout, |t| t.dimmed()),
-                    )
-                    .with_context(|| "failed to read password")
-                    .unwrap()
-                } else {
-                    // password is not required when logging out
-                    String::from("")
-                }
-            }),
+    // password is not required when logging out or for dumb terminals
+    if bit_user.password.is_none() {
+        bit_user.password = match user_from_file.password {
+            Some(password) => Some(password),
+            None if !require_password => None,
+            None if !interactive => return Err(MissingCredentials("password").into()),
+            None => Some(
+                rpassword::prompt_password(
+                    "-> please enter your password: ".if_supports_color(Stdout, |t| t.dimmed()),
+                )
+                .map(Secret::from)
+                .with_context(|| "failed to read password")?,
+            ),
         };
     }
 
@@ -126,5 +215,12 @@ pub fn finalize_bit_user(
         username: bit_user.username.unwrap_or_default(),
         password: bit_user.password.unwrap_or_default(),
         dm: bit_user.dm.unwrap_or_default(),
+        double_stack: bit_user.double_stack.unwrap_or_default(),
+        interface: bit_user.interface,
+        source_ip: bit_user.source_ip,
+        os: bit_user.os,
+        name: bit_user.name,
+        ac_id: bit_user.ac_id,
+        scheme: bit_user.scheme,
     })
 }
@@ -0,0 +1,147 @@
+use crate::client::get_login_state;
+use crate::client::SrunClient;
+use crate::client::SrunLoginState;
+use crate::detect::find_page_redirect;
+use crate::detect::GENERATE_204;
+use crate::transport::HttpTransport;
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use owo_colors::OwoColorize;
+use owo_colors::Stream::Stdout;
+use tokio::net::lookup_host;
+use tokio::time::sleep;
+use tokio::time::timeout;
+
+/// Delay between verification attempts
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// How traffic is checked to get through after login
+#[derive(Debug, Clone)]
+pub enum Probe {
+    /// Fetch a URL without following redirects, which should return 2xx without redirecting in its
+    /// page (204 for `generate_204`)
+    Url(String),
+    /// Resolve a host name
+    Dns(String),
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Url(url) => write!(f, "`{}`", url),
+            Probe::Dns(host) => write!(f, "dns lookup of `{}`", host),
+        }
+    }
+}
+
+/// Post-login verification, the device is only considered online if both the probe succeeds and
+/// `rad_user_info` reports it online
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pub probe: Probe,
+    /// Timeout of each attempt
+    pub timeout: Duration,
+    /// Attempts after the first one fails
+    pub retries: u32,
+}
+
+impl Default for Verification {
+    fn default() -> Self {
+        Verification {
+            probe: Probe::Url(GENERATE_204.into()),
+            timeout: Duration::from_secs(5),
+            retries: 3,
+        }
+    }
+}
+
+async fn run_probe<T: HttpTransport>(client: &T, probe: &Probe) -> Result<()> {
+    match probe {
+        Probe::Url(url) => {
+            // captive portals may answer 200 with an HTML or JS redirect to the login page
+            let resp = client.get_no_redirect(url, &[]).await?;
+            if url == GENERATE_204 && resp.status != 204 {
+                bail!("{} returned {} instead of 204", probe, resp.status)
+            }
+            if !(200..300).contains(&resp.status) {
+                bail!("{} returned {}", probe, resp.status)
+            }
+            if let Some(target) = find_page_redirect(&resp.body) {
+                bail!("{} redirects to `{}` in its page", probe, target)
+            }
+        }
+        Probe::Dns(host) => {
+            let mut addrs = lookup_host((host.as_str(), 0)).await?;
+            if addrs.next().is_none() {
+                bail!("no addresses found for `{}`", host)
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn attempt<T: HttpTransport>(
+    client: &SrunClient<T>,
+    probe: &Probe,
+    verbose: bool,
+) -> Result<SrunLoginState> {
+    run_probe(&client.http_client, probe).await?;
+    let login_state =
+        get_login_state(&client.http_client, &client.portal, client.ip, verbose).await?;
+    if login_state.error != "ok" {
+        bail!("portal reports {}", login_state.error)
+    }
+    Ok(login_state)
+}
+
+/// Verify that the device is actually online after login, retrying until it is or attempts run out
+pub async fn verify_online<T: HttpTransport>(
+    client: &SrunClient<T>,
+    verification: &Verification,
+    verbose: bool,
+) -> Result<SrunLoginState> {
+    let attempts = verification.retries + 1;
+    let mut last_error = anyhow!("not verified");
+    for n in 1..=attempts {
+        let result = timeout(
+            verification.timeout,
+            attempt(client, &verification.probe, verbose),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow!(
+                "timed out after {}s",
+                verification.timeout.as_secs_f64()
+            ))
+        });
+        match result {
+            Ok(login_state) => return Ok(login_state),
+            Err(e) => {
+                if verbose {
+                    println!(
+                        "{} verification attempt {}/{} failed, {:#}",
+                        "bitsrun:".if_supports_color(Stdout, |t| t.blue()),
+                        n,
+                        attempts,
+                        e
+                    );
+                }
+                last_error = e;
+            }
+        }
+        if n < attempts {
+            sleep(RETRY_DELAY).await;
+        }
+    }
+    bail!(
+        "logged in, but the device is not online after {} attempts ({}), {:#}",
+        attempts,
+        verification.probe,
+        last_error
+    )
+}
@@ -2,12 +2,19 @@
  * Encryption algorithm implementation borrowed from
  * https://github.com/zu1k/srun/blob/d47cd60b54503992ffb4eabeb23b27aecb1edf23/src/xencode.rs
  */
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
 use base64::alphabet::Alphabet;
 use base64::engine::Engine;
 use base64::engine::GeneralPurpose;
 use base64::engine::GeneralPurposeConfig;
 
 const BASE64_ALPHABET: &str = "LVoJPiCN2R8G90yg+hmFHuacZ1OWMnrsSTXkYpUq/3dlbfKwv6xztjI7DeBE45QA";
+const DELTA: u32 = 0x9e3779b9; // 0x9e3779b9 = 0x86014019 | 0x183639A0
+
+/// Prefix of the `info` login parameter
+pub const SRBX1_PREFIX: &str = "{SRBX1}";
 
 fn mix(buffer: &[u8], append_size: bool) -> Vec<u32> {
     let mut res: Vec<u32> = buffer
@@ -26,13 +33,16 @@ fn mix(buffer: &[u8], append_size: bool) -> Vec<u32> {
     res
 }
 
-fn splite(buffer: Vec<u32>, include_size: bool) -> Vec<u8> {
+fn splite(buffer: Vec<u32>, include_size: bool) -> Option<Vec<u8>> {
     let len = buffer.len();
-    let size_record = buffer[len - 1];
+    let size_record = *buffer.last()?;
     if include_size {
+        if len < 2 {
+            return None;
+        }
         let size = ((len - 1) * 4) as u32;
         if size_record < size - 3 || size_record > size {
-            return "".into();
+            return None;
         }
     }
 
@@ -40,12 +50,24 @@ fn splite(buffer: Vec<u32>, include_size: bool) -> Vec<u8> {
     if include_size {
         buffer.truncate(size_record as usize);
     }
-    buffer
+    Some(buffer)
 }
 
-pub fn xencode(msg: &str, key: &str) -> Vec<u8> {
+/// The key is a challenge token, it is required to span at least 4 words
+fn check_key(key: &str) -> Result<()> {
+    if key.len() < 16 {
+        bail!(
+            "challenge token too short, expected at least 16 bytes, got {}",
+            key.len()
+        )
+    }
+    Ok(())
+}
+
+pub fn xencode(msg: &str, key: &str) -> Result<Vec<u8>> {
+    check_key(key)?;
     if msg.is_empty() {
-        return vec![];
+        return Ok(vec![]);
     }
     let mut msg = mix(msg.as_bytes(), true);
     let key = mix(key.as_bytes(), false);
@@ -53,12 +75,11 @@ pub fn xencode(msg: &str, key: &str) -> Vec<u8> {
     let len = msg.len();
     let last = len - 1;
     let mut right = msg[last];
-    let c: u32 = 0x9e3779b9; // 0x9e3779b9 = 0x86014019 | 0x183639A0
     let mut d: u32 = 0;
 
     let count = 6 + 52 / msg.len();
     for _ in 0..count {
-        d = d.wrapping_add(c);
+        d = d.wrapping_add(DELTA);
         let e = d >> 2 & 3;
         for p in 0..=last {
             let left = msg[(p + 1) % len];
@@ -69,12 +90,143 @@ pub fn xencode(msg: &str, key: &str) -> Vec<u8> {
             msg[p] = right;
         }
     }
-    splite(msg, false)
+    Ok(splite(msg, false).unwrap_or_default())
 }
 
-pub fn fkbase64(payload: Vec<u8>) -> String {
+/// Inverse of `xencode`, validating the size recorded in the last word
+pub fn xdecode(data: &[u8], key: &str) -> Result<String> {
+    if data.is_empty() {
+        return Ok(String::new());
+    }
+    if !data.len().is_multiple_of(4) || data.len() < 8 {
+        bail!(
+            "invalid xencode length {}, expected at least 8 bytes and a multiple of 4",
+            data.len()
+        )
+    }
+    if key.len() < 16 {
+        bail!("key too short, expected at least 16 bytes")
+    }
+    let mut msg = mix(data, false);
+    let key = mix(key.as_bytes(), false);
+
+    let len = msg.len();
+    let last = len - 1;
+    let count = 6 + 52 / len;
+    let mut d = DELTA.wrapping_mul(count as u32);
+    for _ in 0..count {
+        let e = d >> 2 & 3;
+        for p in (0..=last).rev() {
+            let left = msg[(p + 1) % len];
+            let right = msg[(p + last) % len];
+            let mixed = ((right >> 5) ^ (left << 2))
+                .wrapping_add((left >> 3 ^ right << 4) ^ (d ^ left))
+                .wrapping_add(key[(p & 3) ^ e as usize] ^ right);
+            msg[p] = msg[p].wrapping_sub(mixed);
+        }
+        d = d.wrapping_sub(DELTA);
+    }
+
+    let decoded = splite(msg, true).with_context(|| "invalid size record, wrong key?")?;
+    String::from_utf8(decoded).with_context(|| "decoded data is not valid UTF-8, wrong key?")
+}
+
+fn fkbase64_engine() -> GeneralPurpose {
     let alphabet = Alphabet::new(BASE64_ALPHABET).unwrap();
-    let engine = GeneralPurpose::new(&alphabet, GeneralPurposeConfig::new());
+    GeneralPurpose::new(&alphabet, GeneralPurposeConfig::new())
+}
 
-    engine.encode(payload)
+pub fn fkbase64(payload: Vec<u8>) -> String {
+    fkbase64_engine().encode(payload)
+}
+
+/// Inverse of `fkbase64`
+pub fn fkbase64_decode(encoded: &str) -> Result<Vec<u8>> {
+    fkbase64_engine()
+        .decode(encoded)
+        .with_context(|| "invalid base64 in the custom alphabet")
+}
+
+/// Decode a `{SRBX1}...` login `info` parameter with the challenge `token` it was encrypted with
+pub fn decode_info(info: &str, token: &str) -> Result<String> {
+    let encoded = info
+        .strip_prefix(SRBX1_PREFIX)
+        .with_context(|| format!("info should start with `{}`", SRBX1_PREFIX))?;
+    xdecode(&fkbase64_decode(encoded)?, token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    /// Known-answer vectors `(msg, token, info)` computed with the official portal JS
+    /// (`xEncode` and the custom alphabet `jQuery.base64`)
+    const VECTORS: &[(&str, &str, &str)] = &[
+        ("a", "0123456789abcdef", "{SRBX1}ozirbATTtMD="),
+        ("bitsrun", "0123456789abcdef", "{SRBX1}omsoqNkdAe9B/eMN"),
+        ("abcd", "ffffffffffffffffffffffffffffffff", "{SRBX1}4c0W0Tajkn+="),
+        (
+            r#"{"username":"1120201234","password":"hunter2","ip":"10.62.1.2","acid":"1","enc_ver":"srun_bx1"}"#,
+            "2d6c2cd8d3ea5bd02a6b0f2b4e5e8d63e7a83d7b1c6f10b3bd9c7e3a0d2f5ab3",
+            "{SRBX1}G5HOfsREa5d7f/g92300bQxBcsUlO+kaslEcvwA9bWLr13iNttUH7XEWYkjAKOj1L7yPUVCtEQLu6sfrHKM31T2OrBnB1Wen4H5Bx/m+Rh+C8ywE01l/UivcavGkgBBy/KOWq+==",
+        ),
+        (
+            r#"{"acid":"8","enc_ver":"srun_bx1","ip":"10.194.34.56","password":"p@ss w0rd!","username":"3220230001"}"#,
+            "b4f4b0e3a9d2c41e8f5c6d7a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e",
+            "{SRBX1}oFB/h3vq8oxZClZQbbO5yDSnKu5uoz0HzErz3tBBxz6KYmiTbxSaDHWrUtDhPUqdRMhIEAA2BeVG9rHjFs30n1EGm4CJbgqEYTUGv0YhqDc6ict030eQfQaJb79dJqpJ7JZBPqlM6gY3p07k",
+        ),
+    ];
+
+    #[test]
+    fn known_answer_vectors() {
+        for (msg, token, info) in VECTORS {
+            let encoded = xencode(msg, token).unwrap();
+            assert_eq!(format!("{}{}", SRBX1_PREFIX, fkbase64(encoded)), *info);
+            assert_eq!(decode_info(info, token).unwrap(), *msg);
+        }
+    }
+
+    #[test]
+    fn short_or_empty_token_is_an_error() {
+        for token in ["", "0", "0123456789abcde"] {
+            assert!(xencode("bitsrun", token).is_err());
+            assert!(xdecode(&[0; 8], token).is_err());
+        }
+    }
+
+    #[test]
+    fn empty_message() {
+        assert!(xencode("", "0123456789abcdef").unwrap().is_empty());
+        assert_eq!(xdecode(&[], "0123456789abcdef").unwrap(), "");
+    }
+
+    #[test]
+    fn malformed_data_is_an_error() {
+        let token = "0123456789abcdef";
+        assert!(xdecode(&[0; 3], token).is_err());
+        assert!(xdecode(&[0; 4], token).is_err());
+        assert!(decode_info("ozirbATTtMD=", token).is_err());
+        assert!(decode_info("{SRBX1}!!!!", token).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(msg in ".*", token in "[0-9a-f]{16,64}") {
+            let encoded = xencode(&msg, &token).unwrap();
+            let info = format!("{}{}", SRBX1_PREFIX, fkbase64(encoded));
+            prop_assert_eq!(decode_info(&info, &token).unwrap(), msg);
+        }
+
+        #[test]
+        fn fkbase64_roundtrip(payload in proptest::collection::vec(any::<u8>(), 0..256)) {
+            prop_assert_eq!(fkbase64_decode(&fkbase64(payload.clone())).unwrap(), payload);
+        }
+
+        #[test]
+        fn decode_never_panics(data in proptest::collection::vec(any::<u8>(), 0..256), token in ".*") {
+            let _ = xdecode(&data, &token);
+        }
+    }
 }
@@ -0,0 +1,48 @@
+/* SRun portal login plugin, trimmed to the parts bitsrun probes */
+(function ($) {
+  $.fn.srun_portal = function (options) {
+    var _this = this,
+      enc = "srun_bx1",
+      n = 200,
+      type = 1;
+
+    var url = {
+      auth: "/cgi-bin/srun_portal",
+      challenge: "/cgi-bin/get_challenge",
+      info: "/cgi-bin/rad_user_info"
+    };
+
+    _this.login = function (data, callback) {
+      $.get(url.challenge, { username: data.username, ip: data.ip }, function (res) {
+        var token = res.challenge;
+        var i = $.base64.encode($.xEncode(JSON.stringify({
+          username: data.username,
+          password: data.password,
+          ip: data.ip,
+          acid: data.ac_id,
+          enc_ver: enc
+        }), token));
+        var hmd5 = $.md5(data.password, token);
+        var chkstr = token + data.username;
+        chkstr += token + hmd5;
+        chkstr += token + data.ac_id;
+        chkstr += token + data.ip;
+        chkstr += token + n;
+        chkstr += token + type;
+        chkstr += token + "{SRBX1}" + i;
+        $.get(url.auth, {
+          action: "login",
+          username: data.username,
+          password: "{MD5}" + hmd5,
+          ac_id: data.ac_id,
+          ip: data.ip,
+          chksum: $.sha1(chkstr),
+          info: "{SRBX1}" + i,
+          n: n,
+          type: type
+        }, callback, "jsonp");
+      }, "jsonp");
+    };
+    return _this;
+  };
+})(jQuery);
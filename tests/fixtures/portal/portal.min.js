@@ -0,0 +1,2 @@
+/* SRun portal bundle, minified and trimmed to the parts bitsrun probes */
+!function(e){var t={enc_ver:"srun_bx1",n:200,type:1,auth:"/cgi-bin/srun_portal"};function r(e){for(var n=0;n<e.length;n++)if(e.charCodeAt(n)>255)return!1;return!0}function o(e,r){var o=t.n==e.n&&t.type===e.type;return o?r:null}e.srunLogin=function(e,n,i){return r(e.username)?e.get(t.auth,{action:"login",n:t.n,type:t.type,info:i,chksum:n}):null}}(window.jQuery);